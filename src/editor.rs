@@ -0,0 +1,599 @@
+//! In-place editing of existing DiffX documents.
+//!
+//! [`DocumentEditor`] parses a document much like [`DiffxParser`][super::DiffxParser]
+//! does, but keeps track of each section's original byte span alongside its
+//! decoded fields. Sections that are never touched are re-emitted verbatim
+//! from the source buffer; only sections reached through
+//! [`DocumentEditor::section_mut`] and actually mutated are re-serialized,
+//! so a round trip of an unmodified document is byte-for-byte identical to
+//! its input.
+
+use std::io;
+use std::io::Write;
+use std::str;
+
+use {decode_section_content, ContentEncoding, Encoding, SectionContent};
+use grammar;
+
+/// An error encountered while parsing a document for editing.
+#[derive(Debug, PartialEq, Eq)]
+pub struct EditorError(String);
+
+/// The content of a section open for editing.
+#[derive(Debug, PartialEq, Eq)]
+enum EditSectionContent<'a> {
+    ChildSections(Vec<EditSection<'a>>),
+
+    /// The section's original, borrowed data, unless it has been replaced.
+    EncodedData(&'a str),
+    RawData(&'a [u8]),
+
+    /// Data that has replaced the section's original content.
+    OwnedEncodedData(String),
+    OwnedRawData(Vec<u8>),
+
+    /// Data decompressed from a `content-encoding` payload.
+    ///
+    /// Owned, since decompression cannot produce a slice borrowed from the
+    /// original input.
+    DecodedEncodedData(String),
+    DecodedRawData(Vec<u8>),
+}
+
+/// A single section of a document open for editing.
+#[derive(Debug, PartialEq, Eq)]
+pub struct EditSection<'a> {
+    /// The exact bytes of this section (header through its trailing blank
+    /// line) as it appeared in the source document.
+    span: &'a [u8],
+
+    /// The exact bytes of just this section's header line, as it appeared
+    /// in the source document.
+    header_span: &'a [u8],
+
+    /// Whether this section's header or content has been replaced since
+    /// parsing; if `false`, `span` (or, for a section with child sections,
+    /// `header_span`, followed by each child in turn) is emitted verbatim
+    /// instead of being reconstructed from the fields below.
+    dirty: bool,
+
+    title: String,
+    encoding: Option<Encoding>,
+    options: Vec<(String, String)>,
+    content: EditSectionContent<'a>,
+}
+
+impl<'a> EditSection<'a> {
+    fn child_mut(&mut self, path: &[&str]) -> Option<&mut EditSection<'a>> {
+        let (head, rest) = match path.split_first() {
+            Some(parts) => parts,
+            None => return Some(self),
+        };
+
+        match self.content {
+            EditSectionContent::ChildSections(ref mut children) => {
+                children.iter_mut()
+                    .find(|child| child.title == *head)
+                    .and_then(|child| child.child_mut(rest))
+            }
+            _ => None,
+        }
+    }
+
+    /// Set (or overwrite) an option on this section's header.
+    ///
+    /// Panics if `key` or `value` contains a byte the DiffX grammar does not
+    /// allow in an option key or value: both are written byte-for-byte into
+    /// the header line, so an unvalidated value could otherwise inject a
+    /// `,`-separated option of its own, or even (via an embedded `\n`) a
+    /// whole extra header.
+    pub fn set_option<S: Into<String>>(&mut self, key: &str, value: S) {
+        let value = value.into();
+        assert_valid_option_str("option key", key);
+        assert_valid_option_str("option value", &value);
+        match self.options.iter_mut().find(|&&mut (ref k, _)| k == key) {
+            Some(existing) => existing.1 = value,
+            None => self.options.push((key.to_string(), value)),
+        }
+        self.dirty = true;
+    }
+
+    /// Replace this section's content with new UTF-8 encoded data.
+    ///
+    /// Panics if this section currently has child sections rather than data.
+    /// This also sets (or overwrites) the section's `encoding` option to
+    /// `utf-8`, since the header must keep advertising the encoding that
+    /// matches the content actually written, regardless of what it
+    /// previously declared or inherited.
+    pub fn set_encoded_data<S: Into<String>>(&mut self, data: S) {
+        match self.content {
+            EditSectionContent::ChildSections(_) => {
+                panic!("cannot replace child sections with encoded data")
+            }
+            _ => {}
+        }
+        self.content = EditSectionContent::OwnedEncodedData(data.into());
+        self.encoding = Some(Encoding::Utf8);
+        self.set_option("encoding", encoding_str(Encoding::Utf8));
+        // The replaced content is never compressed, so a `content-encoding`
+        // inherited from the original (possibly compressed) content would
+        // otherwise mislabel it.
+        self.options.retain(|&(ref k, _)| k != "content-encoding");
+    }
+
+    /// Replace this section's content with new raw binary data.
+    ///
+    /// Panics if this section currently has child sections rather than data.
+    /// This also sets (or overwrites) the section's `encoding` option to
+    /// `binary`, since the header must keep advertising the encoding that
+    /// matches the content actually written, regardless of what it
+    /// previously declared or inherited.
+    pub fn set_raw_data<D: Into<Vec<u8>>>(&mut self, data: D) {
+        match self.content {
+            EditSectionContent::ChildSections(_) => {
+                panic!("cannot replace child sections with raw data")
+            }
+            _ => {}
+        }
+        self.content = EditSectionContent::OwnedRawData(data.into());
+        self.encoding = Some(Encoding::Binary);
+        self.set_option("encoding", encoding_str(Encoding::Binary));
+        // The replaced content is never compressed, so a `content-encoding`
+        // inherited from the original (possibly compressed) content would
+        // otherwise mislabel it.
+        self.options.retain(|&(ref k, _)| k != "content-encoding");
+    }
+
+    fn write_header<W: Write>(&self, w: &mut W, depth: usize, content_length: Option<usize>) -> io::Result<()> {
+        write!(w, "#")?;
+        for _ in 0..depth {
+            write!(w, ".")?;
+        }
+        write!(w, "{}:", self.title)?;
+
+        let mut options = self.options.clone();
+        if let Some(content_length) = content_length {
+            options.push(("content-length".into(), content_length.to_string()));
+        }
+
+        if !options.is_empty() {
+            write!(w, " ")?;
+            for (i, &(ref key, ref value)) in options.iter().enumerate() {
+                if i > 0 {
+                    write!(w, ",")?;
+                }
+                write!(w, "{}={}", key, value)?;
+            }
+        }
+        write!(w, "\n")
+    }
+
+    fn write_to<W: Write>(&self, w: &mut W, depth: usize, parent_encoding: Encoding) -> io::Result<()> {
+        let encoding = self.encoding.unwrap_or(parent_encoding);
+
+        // A section with child sections always recurses into them, since
+        // one of its descendants (rather than this section itself) may be
+        // the one that was edited; only a leaf section, and only if it was
+        // not itself edited, can be emitted as a single verbatim span.
+        match self.content {
+            EditSectionContent::ChildSections(ref children) => {
+                if self.dirty {
+                    self.write_header(w, depth, None)?;
+                } else {
+                    w.write_all(self.header_span)?;
+                }
+                for child in children {
+                    child.write_to(w, depth + 1, encoding)?;
+                }
+                Ok(())
+            }
+            _ if !self.dirty => w.write_all(self.span),
+            EditSectionContent::EncodedData(data) => {
+                self.write_header(w, depth, Some(data.len()))?;
+                w.write_all(data.as_bytes())?;
+                write!(w, "\n")
+            }
+            EditSectionContent::OwnedEncodedData(ref data) => {
+                self.write_header(w, depth, Some(data.len()))?;
+                w.write_all(data.as_bytes())?;
+                write!(w, "\n")
+            }
+            EditSectionContent::RawData(data) => {
+                self.write_header(w, depth, Some(data.len()))?;
+                w.write_all(data)?;
+                write!(w, "\n")
+            }
+            EditSectionContent::OwnedRawData(ref data) => {
+                self.write_header(w, depth, Some(data.len()))?;
+                w.write_all(data)?;
+                write!(w, "\n")
+            }
+            EditSectionContent::DecodedEncodedData(ref data) => {
+                self.write_header(w, depth, Some(data.len()))?;
+                w.write_all(data.as_bytes())?;
+                write!(w, "\n")
+            }
+            EditSectionContent::DecodedRawData(ref data) => {
+                self.write_header(w, depth, Some(data.len()))?;
+                w.write_all(data)?;
+                write!(w, "\n")
+            }
+        }
+    }
+}
+
+/// A document opened for editing.
+///
+/// Construct one with [`DocumentEditor::parse`], mutate sections in place
+/// with [`DocumentEditor::section_mut`], and get the edited document back
+/// out with [`DocumentEditor::write_to`]/[`DocumentEditor::to_bytes`].
+pub struct DocumentEditor<'a> {
+    root: EditSection<'a>,
+}
+
+impl<'a> DocumentEditor<'a> {
+    /// Parse an existing DiffX document for editing.
+    pub fn parse(source: &'a [u8]) -> Result<DocumentEditor<'a>, EditorError> {
+        let (root, rest) = scan_section(source, 0, Encoding::Binary)?;
+        if !rest.is_empty() {
+            return Err(EditorError("trailing data after root section".into()));
+        }
+        Ok(DocumentEditor { root: root })
+    }
+
+    /// Look up a section by path, e.g. `&["foo", "bar"]` for `bar` nested
+    /// inside a top-level `foo` section, for mutation.
+    ///
+    /// The root `diffx` section itself is reached with an empty path.
+    pub fn section_mut(&mut self, path: &[&str]) -> Option<&mut EditSection<'a>> {
+        self.root.child_mut(path)
+    }
+
+    /// Serialize the edited document, writing it to `w`.
+    ///
+    /// Sections that were never mutated are written out byte-for-byte as
+    /// they were parsed; only mutated sections (and, transitively, their
+    /// ancestors' header lines are left untouched since only the mutated
+    /// section's own bytes change) are reconstructed.
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.root.write_to(w, 0, Encoding::Binary)
+    }
+
+    /// Serialize the edited document into a newly allocated byte vector.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.write_to(&mut bytes).unwrap();
+        bytes
+    }
+}
+
+// A lightweight scan that mirrors the grammar `DiffxParser` implements, but
+// additionally records the exact byte span consumed by each
+// section so untouched sections can be re-emitted verbatim.
+fn scan_section<'a>(input: &'a [u8],
+                     depth: usize,
+                     parent_encoding: Encoding)
+                     -> Result<(EditSection<'a>, &'a [u8]), EditorError> {
+    let start = input;
+
+    if input.first() != Some(&b'#') {
+        return Err(EditorError("expected '#' at start of section header".into()));
+    }
+    let mut rest = &input[1..];
+
+    let mut header_depth = 0;
+    while rest.first() == Some(&b'.') {
+        header_depth += 1;
+        rest = &rest[1..];
+    }
+    if header_depth != depth {
+        return Err(EditorError(format!("expected section with depth {}, found {}", depth, header_depth)));
+    }
+
+    let title_len = rest.iter().take_while(|&&c| grammar::is_section_header_char(c)).count();
+    let title = str::from_utf8(&rest[..title_len])
+        .map_err(|_| EditorError("section title was not valid UTF-8".into()))?
+        .to_string();
+    rest = &rest[title_len..];
+
+    if rest.first() != Some(&b':') {
+        return Err(EditorError("expected ':' after section title".into()));
+    }
+    rest = &rest[1..];
+
+    let header_line_len = rest.iter()
+        .position(|&c| c == b'\n')
+        .ok_or_else(|| EditorError("unterminated section header".into()))?;
+    let header_rest = &rest[..header_line_len];
+    rest = &rest[header_line_len + 1..];
+
+    let header_span = &start[..start.len() - rest.len()];
+
+    let mut options = parse_options(header_rest)?;
+
+    // The last declaration of a duplicate key wins, matching
+    // `Options::get` and the rest of the crate's lenient-mode behavior.
+    let encoding = match options.iter().rev().find(|&&(ref k, _)| k == "encoding") {
+        Some(&(_, ref v)) => {
+            Some(Encoding::from_str(v).ok_or_else(|| EditorError(format!("unknown encoding {}", v)))?)
+        }
+        None => None,
+    };
+    let effective_encoding = encoding.unwrap_or(parent_encoding);
+
+    let content_length = match options.iter().rev().find(|&&(ref k, _)| k == "content-length") {
+        Some(&(_, ref v)) => {
+            Some(v.parse::<usize>().map_err(|_| EditorError(format!("invalid content-length {}", v)))?)
+        }
+        None => None,
+    };
+
+    // `content-length` is recomputed by `write_header` whenever a section is
+    // rewritten, so it is not kept alongside the other options; otherwise an
+    // edited section would end up with two `content-length` entries.
+    options.retain(|&(ref k, _)| k != "content-length");
+
+    let content_encoding = match options.iter().rev().find(|&&(ref k, _)| k == "content-encoding") {
+        Some(&(_, ref v)) => {
+            ContentEncoding::from_str(v)
+                .ok_or_else(|| EditorError(format!("unknown content-encoding {}", v)))?
+        }
+        None => ContentEncoding::Identity,
+    };
+
+    let (content, after_content) = if let Some(content_length) = content_length {
+        let required_len = content_length.checked_add(1)
+            .ok_or_else(|| EditorError("content-length is too large".into()))?;
+        if rest.len() < required_len {
+            return Err(EditorError("content-length exceeds remaining input".into()));
+        }
+        let (data, after) = rest.split_at(content_length);
+        if after.first() != Some(&b'\n') {
+            return Err(EditorError("content was not followed by a blank line".into()));
+        }
+        let content = match content_encoding {
+            ContentEncoding::Identity => {
+                match effective_encoding {
+                    Encoding::Binary => EditSectionContent::RawData(data),
+                    Encoding::Utf8 => {
+                        EditSectionContent::EncodedData(str::from_utf8(data)
+                            .map_err(|_| EditorError("content was not valid UTF-8".into()))?)
+                    }
+                }
+            }
+            ContentEncoding::Gzip | ContentEncoding::Deflate => {
+                let (decoded, _) = decode_section_content(data, content_encoding, effective_encoding, None)
+                    .map_err(|e| EditorError(e.to_string()))?;
+                match decoded {
+                    SectionContent::DecodedEncodedData(s) => EditSectionContent::DecodedEncodedData(s),
+                    SectionContent::DecodedRawData(d) => EditSectionContent::DecodedRawData(d),
+                    _ => unreachable!(),
+                }
+            }
+        };
+        (content, &after[1..])
+    } else {
+        let child_depth = depth + 1;
+        let mut children = Vec::new();
+        let mut remaining = rest;
+        loop {
+            match grammar::peek_header_depth(remaining) {
+                None => break,
+                Some(found_depth) if found_depth < child_depth => break,
+                Some(found_depth) if found_depth > child_depth => {
+                    return Err(EditorError(format!("expected section with depth {}, found {}",
+                                                    child_depth,
+                                                    found_depth)));
+                }
+                Some(_) => {
+                    let (child, after) = scan_section(remaining, child_depth, effective_encoding)?;
+                    children.push(child);
+                    remaining = after;
+                }
+            }
+        }
+        if children.is_empty() {
+            return Err(EditorError("section had neither content-length nor child sections".into()));
+        }
+        (EditSectionContent::ChildSections(children), remaining)
+    };
+
+    let span_len = start.len() - after_content.len();
+    let span = &start[..span_len];
+
+    Ok((EditSection {
+            span: span,
+            header_span: header_span,
+            dirty: false,
+            title: title,
+            encoding: encoding,
+            options: options,
+            content: content,
+        },
+        after_content))
+}
+
+fn parse_options<'a>(input: &'a [u8]) -> Result<Vec<(String, String)>, EditorError> {
+    grammar::option_pairs(grammar::trim(input))
+        .map(|pair| {
+            let (key, value) = pair.map_err(|_| EditorError("malformed option".into()))?;
+            Ok((key.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+// Mirrors `DiffxParser`'s grammar for an option key or value, both of which
+// must be non-empty. Used to validate options set through `set_option`
+// before they are written back into a header line, since unlike a parsed
+// option (which already went through the grammar), a caller-supplied one
+// has not.
+fn assert_valid_option_str(kind: &str, s: &str) {
+    if s.is_empty() || !s.bytes().all(grammar::is_option_char) {
+        panic!("{} {:?} must be non-empty and contain only characters allowed in a DiffX option", kind, s);
+    }
+}
+
+fn encoding_str(encoding: Encoding) -> &'static str {
+    match encoding {
+        Encoding::Utf8 => "utf-8",
+        Encoding::Binary => "binary",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Encoding;
+
+    const DOC: &'static [u8] = b"\
+#diffx: version=1.0,encoding=utf-8
+#.foo: content-length=14
+Hello, \xE4\xB8\x96\xE7\x95\x8C
+
+#.bar: content-length=16,encoding=binary
+Goodbye, world!
+
+";
+
+    #[test]
+    fn test_parse_and_round_trip_unmodified() {
+        let editor = DocumentEditor::parse(DOC).unwrap();
+        assert_eq!(editor.to_bytes(), DOC);
+    }
+
+    #[test]
+    fn test_edit_content_preserves_siblings() {
+        let mut editor = DocumentEditor::parse(DOC).unwrap();
+        editor.section_mut(&["foo"]).unwrap().set_encoded_data("Hi!\n");
+
+        let bytes = editor.to_bytes();
+        let reparsed = DocumentEditor::parse(&bytes).unwrap();
+
+        match reparsed.root.content {
+            EditSectionContent::ChildSections(ref children) => {
+                let foo = children.iter().find(|c| c.title == "foo").unwrap();
+                assert_eq!(foo.content, EditSectionContent::EncodedData("Hi!\n"));
+
+                let bar = children.iter().find(|c| c.title == "bar").unwrap();
+                assert_eq!(bar.span,
+                           &b"#.bar: content-length=16,encoding=binary\nGoodbye, world!\n\n"[..]);
+            }
+            ref other => panic!("expected child sections, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_child_nested_too_deep_is_a_propagated_error_not_end_of_children() {
+        // `bar` is nested three deep directly under `foo`, skipping depth 2
+        // entirely; this must be reported as the genuine nesting error it
+        // is, not silently treated as "foo has no children".
+        let doc = b"\
+#diffx: encoding=utf-8
+#.foo:
+#...bar: content-length=3
+abc
+
+";
+        match DocumentEditor::parse(doc) {
+            Err(EditorError(msg)) => assert!(msg.contains("depth"), "unexpected error: {}", msg),
+            other => panic!("expected a depth error, got {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "flate2")]
+    #[test]
+    fn test_gzip_content_encoding_is_decompressed() {
+        use std::io::Write;
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all("Hello, 世界\n".as_bytes()).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let document = format!("#diffx: content-encoding=gzip,content-length={},encoding=utf-8\n",
+                                compressed.len())
+            .into_bytes()
+            .into_iter()
+            .chain(compressed)
+            .chain(b"\n".iter().cloned())
+            .collect::<Vec<u8>>();
+
+        let editor = DocumentEditor::parse(&document).expect("gzip-compressed section should parse");
+        match editor.root.content {
+            EditSectionContent::DecodedEncodedData(ref data) => assert_eq!(data, "Hello, 世界\n"),
+            ref other => panic!("expected decoded encoded data, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_duplicate_content_length_is_last_wins() {
+        let doc = b"#diffx: content-length=5,content-length=3\nabc\n";
+        let editor = DocumentEditor::parse(doc).unwrap();
+        match editor.root.content {
+            EditSectionContent::RawData(data) => assert_eq!(data, &b"abc"[..]),
+            ref other => panic!("expected raw data, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_duplicate_encoding_is_last_wins() {
+        let doc = b"#diffx: encoding=binary,encoding=utf-8,content-length=3\nabc\n";
+        let editor = DocumentEditor::parse(doc).unwrap();
+        assert_eq!(editor.root.encoding, Some(Encoding::Utf8));
+        match editor.root.content {
+            EditSectionContent::EncodedData(data) => assert_eq!(data, "abc"),
+            ref other => panic!("expected encoded data, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_set_option() {
+        let mut editor = DocumentEditor::parse(DOC).unwrap();
+        editor.section_mut(&["foo"]).unwrap().set_option("title", "greeting");
+
+        let bytes = editor.to_bytes();
+        assert!(str::from_utf8(&bytes).unwrap().contains("title=greeting"));
+
+        // The untouched `bar` section is byte-identical to the source.
+        let reparsed = DocumentEditor::parse(&bytes).unwrap();
+        match reparsed.root.content {
+            EditSectionContent::ChildSections(ref children) => {
+                let bar = children.iter().find(|c| c.title == "bar").unwrap();
+                assert_eq!(bar.span,
+                           &b"#.bar: content-length=16,encoding=binary\nGoodbye, world!\n\n"[..]);
+            }
+            ref other => panic!("expected child sections, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_set_raw_data_on_utf8_section_updates_encoding() {
+        let mut editor = DocumentEditor::parse(DOC).unwrap();
+        editor.section_mut(&["foo"]).unwrap().set_raw_data(vec![0xFF, 0xFE, 0, 1, 2]);
+
+        let bytes = editor.to_bytes();
+        let reparsed = DocumentEditor::parse(&bytes).unwrap();
+
+        match reparsed.root.content {
+            EditSectionContent::ChildSections(ref children) => {
+                let foo = children.iter().find(|c| c.title == "foo").unwrap();
+                assert_eq!(foo.encoding, Some(Encoding::Binary));
+                assert_eq!(foo.content, EditSectionContent::RawData(&[0xFF, 0xFE, 0, 1, 2][..]));
+            }
+            ref other => panic!("expected child sections, got {:?}", other),
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_set_option_rejects_value_with_comma_and_equals() {
+        let mut editor = DocumentEditor::parse(DOC).unwrap();
+        editor.section_mut(&["foo"]).unwrap().set_option("title", "v=1,injected=2");
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_set_option_rejects_key_with_newline() {
+        let mut editor = DocumentEditor::parse(DOC).unwrap();
+        editor.section_mut(&["foo"]).unwrap().set_option("title\n#.evil: content-length=0", "x");
+    }
+}
@@ -0,0 +1,198 @@
+//! Shared low-level grammar for the DiffX header syntax.
+//!
+//! `lib.rs`'s own-buffer parser, `incremental`'s streaming parser, and
+//! `editor`'s byte-span-tracking parser each need to recognize the same
+//! section-title and option character classes, split a header line into its
+//! depth/title/options pieces, and split an option list into key/value
+//! pairs. Keeping that here means those rules only have to be gotten right
+//! once, rather than drifting across three independent reimplementations
+//! (as they did: duplicate option keys, `content-encoding` handling, and
+//! child-parsing error propagation had each diverged between copies before
+//! this module existed). `lib.rs` is the exception for option lists: it
+//! splits them with its own `option_list` rather than [`option_pairs`].
+
+use std::str;
+
+/// Characters allowed in a section title.
+pub(crate) fn is_section_header_char(c: u8) -> bool {
+    match c {
+        b'a'...b'z' | b'A'...b'Z' | b'-' => true,
+        _ => false,
+    }
+}
+
+/// Characters allowed in an option key or value.
+pub(crate) fn is_option_char(c: u8) -> bool {
+    match c {
+        b'a'...b'z' | b'A'...b'Z' | b'0'...b'9' | b'-' | b'_' | b'.' => true,
+        _ => false,
+    }
+}
+
+/// Trim leading and trailing ASCII spaces.
+pub(crate) fn trim(input: &[u8]) -> &[u8] {
+    let input = match input.iter().position(|&c| c != b' ') {
+        Some(i) => &input[i..],
+        None => return &input[0..0],
+    };
+    match input.iter().rposition(|&c| c != b' ') {
+        Some(i) => &input[..i + 1],
+        None => &input[0..0],
+    }
+}
+
+/// The depth, title, and unparsed option-list span of a single header line
+/// (e.g. `..foo: encoding=utf-8`, with the leading `#` already stripped and
+/// the line already bounded to its terminating `\n`).
+pub(crate) struct HeaderLine<'a> {
+    pub(crate) depth: usize,
+    pub(crate) title: &'a str,
+    /// Everything after the title's terminating `:`, not yet trimmed of
+    /// surrounding spaces or split into options.
+    pub(crate) rest: &'a [u8],
+}
+
+/// Scan a header line's depth, title, and trailing option-list span.
+///
+/// `line` must already have the header's leading `#` stripped and be
+/// bounded to (but not include) its terminating `\n`.
+pub(crate) fn scan_header_line(line: &[u8]) -> Result<HeaderLine, &'static str> {
+    let depth = line.iter().take_while(|&&c| c == b'.').count();
+    let line = &line[depth..];
+
+    // Safe due to is_section_header_char only accepting a limited subset of
+    // ASCII.
+    let title_len = line.iter().take_while(|&&c| is_section_header_char(c)).count();
+    let title = unsafe { str::from_utf8_unchecked(&line[..title_len]) };
+    let line = &line[title_len..];
+
+    let rest = match line.first() {
+        Some(&b':') => &line[1..],
+        _ => return Err("expected ':' after section title"),
+    };
+
+    Ok(HeaderLine { depth: depth, title: title, rest: rest })
+}
+
+/// The depth of the next section header in `input`, or `None` if `input`
+/// holds no further section (i.e. there are no more children to parse).
+///
+/// Used to tell "the next header belongs to a shallower section, so this
+/// section has no more children" apart from a genuine parse error (a
+/// malformed header, or a resource limit being exceeded), which must be
+/// propagated rather than silently treated as the end of the child list.
+pub(crate) fn peek_header_depth(input: &[u8]) -> Option<usize> {
+    if input.first() != Some(&b'#') {
+        return None;
+    }
+    Some(input[1..].iter().take_while(|&&c| c == b'.').count())
+}
+
+/// An error encountered while splitting an option list into key/value
+/// pairs.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum OptionListError {
+    /// A pair had no `=` separating a key from a value.
+    MissingEquals,
+    /// A key or value was empty, or contained a byte `is_option_char`
+    /// rejects.
+    InvalidChar,
+}
+
+/// Lazily split `input` into its raw `(key, value)` pairs, in declaration
+/// order, without allocating an intermediate `Vec`. Each key and value is
+/// validated against [`is_option_char`] and required to be non-empty.
+///
+/// `input` should already be trimmed; an empty `input` yields no pairs.
+pub(crate) fn option_pairs(input: &[u8]) -> OptionPairs {
+    OptionPairs { rest: if input.is_empty() { None } else { Some(input) } }
+}
+
+pub(crate) struct OptionPairs<'a> {
+    rest: Option<&'a [u8]>,
+}
+
+impl<'a> Iterator for OptionPairs<'a> {
+    type Item = Result<(&'a str, &'a str), OptionListError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let chunk = match self.rest.take() {
+            Some(chunk) => chunk,
+            None => return None,
+        };
+
+        let (pair, next_rest) = match chunk.iter().position(|&c| c == b',') {
+            Some(i) => (&chunk[..i], Some(&chunk[i + 1..])),
+            None => (chunk, None),
+        };
+        self.rest = next_rest;
+
+        Some(split_pair(pair))
+    }
+}
+
+fn split_pair(pair: &[u8]) -> Result<(&str, &str), OptionListError> {
+    let eq = match pair.iter().position(|&c| c == b'=') {
+        Some(eq) => eq,
+        None => return Err(OptionListError::MissingEquals),
+    };
+    let (key, value) = pair.split_at(eq);
+    let value = &value[1..];
+
+    if key.is_empty() || value.is_empty() || !key.iter().cloned().all(is_option_char) ||
+       !value.iter().cloned().all(is_option_char) {
+        return Err(OptionListError::InvalidChar);
+    }
+
+    // Safe due to is_option_char only accepting a limited subset of ASCII.
+    unsafe { Ok((str::from_utf8_unchecked(key), str::from_utf8_unchecked(value))) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scan_header_line() {
+        let header = scan_header_line(b"..foo: encoding=utf-8").unwrap();
+        assert_eq!(header.depth, 2);
+        assert_eq!(header.title, "foo");
+        assert_eq!(header.rest, &b" encoding=utf-8"[..]);
+    }
+
+    #[test]
+    fn test_scan_header_line_missing_colon_is_an_error() {
+        assert!(scan_header_line(b"foo").is_err());
+    }
+
+    #[test]
+    fn test_peek_header_depth() {
+        assert_eq!(peek_header_depth(b"#.foo: content-length=0\n"), Some(1));
+        assert_eq!(peek_header_depth(b""), None);
+        assert_eq!(peek_header_depth(b"not a header"), None);
+    }
+
+    #[test]
+    fn test_option_pairs() {
+        let pairs: Result<Vec<_>, _> = option_pairs(b"foo=bar,baz=qux").collect();
+        assert_eq!(pairs, Ok(vec![("foo", "bar"), ("baz", "qux")]));
+    }
+
+    #[test]
+    fn test_option_pairs_empty_input_yields_no_pairs() {
+        let pairs: Result<Vec<_>, _> = option_pairs(b"").collect();
+        assert_eq!(pairs, Ok(vec![]));
+    }
+
+    #[test]
+    fn test_option_pairs_missing_equals_is_an_error() {
+        let pairs: Result<Vec<_>, _> = option_pairs(b"foo").collect();
+        assert_eq!(pairs, Err(OptionListError::MissingEquals));
+    }
+
+    #[test]
+    fn test_option_pairs_rejects_disallowed_char() {
+        let pairs: Result<Vec<_>, _> = option_pairs(b"foo=b r").collect();
+        assert_eq!(pairs, Err(OptionListError::InvalidChar));
+    }
+}
@@ -0,0 +1,430 @@
+//! Incremental, partial-input parsing for streaming DiffX documents.
+//!
+//! The main `DiffxParser` entry points require the whole document (or
+//! section) to already be in memory, and return a hard parse error if the
+//! input is merely truncated. That is a poor fit for a caller reading a
+//! DiffX section off a socket one `read()` at a time. `parse_partial`
+//! instead reports [`Status::Partial`] when it cannot yet tell whether the
+//! input is malformed or simply incomplete, so the caller can append more
+//! bytes and retry.
+
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::str;
+
+use memchr::memchr;
+
+use {ContentEncoding, Encoding, Options, Section, SectionContent};
+use grammar;
+
+/// The result of an incremental parse attempt.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Status<T> {
+    /// A value was fully parsed. `consumed_bytes` is exactly how much of
+    /// the input it occupied, so the caller can advance its buffer past it.
+    Complete {
+        value: T,
+        consumed_bytes: usize,
+    },
+
+    /// Not enough input was available to determine whether parsing would
+    /// succeed or fail. The caller should append more bytes and retry with
+    /// the same logical position (i.e. the original input, plus whatever
+    /// was appended).
+    Partial,
+}
+
+/// An error encountered while incrementally parsing a section.
+///
+/// Unlike `Status::Partial`, this indicates the input parsed so far is
+/// unambiguously invalid DiffX, regardless of how many more bytes follow.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Error(String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Attempt to parse a single top-level (`depth` 0) section from `input`,
+/// defaulting to [`Encoding::Binary`][super::Encoding::Binary] as the
+/// parent encoding, as `DiffxParser::section(0, Encoding::Binary)` does.
+///
+/// `at_eof` tells the parser whether more bytes could still arrive: pass
+/// `false` while reading from a socket that might yield more data, and
+/// `true` once the caller knows the stream has ended (e.g. on EOF). Without
+/// this, a section with child sections can never be reported as
+/// `Status::Complete`, since running out of input is otherwise always
+/// ambiguous with "a sibling header just hasn't arrived yet".
+pub fn parse_partial<'a>(input: &'a [u8], at_eof: bool) -> Result<Status<(&'a str, Section<'a>)>, Error> {
+    parse_section_partial(input, 0, Encoding::Binary, at_eof)
+}
+
+/// Attempt to parse a single section of the given `depth` from `input`,
+/// inheriting `parent_encoding` if the section does not declare its own.
+///
+/// This mirrors `DiffxParser::section`, but returns `Ok(Status::Partial)`
+/// instead of an error when `input` is merely truncated and `at_eof` is
+/// `false`. See [`parse_partial`] for the meaning of `at_eof`.
+pub fn parse_section_partial<'a>(input: &'a [u8],
+                                  depth: usize,
+                                  parent_encoding: Encoding,
+                                  at_eof: bool)
+                                  -> Result<Status<(&'a str, Section<'a>)>, Error> {
+    let header = match parse_header_partial(input, at_eof)? {
+        Status::Complete { value, consumed_bytes } => (value, consumed_bytes),
+        Status::Partial => return Ok(Status::Partial),
+    };
+    let (header, header_len) = header;
+
+    if header.depth != depth {
+        return Err(Error(format!("expected section with depth {}, found {}", depth, header.depth)));
+    }
+
+    let encoding = match header.encoding {
+        Some(encoding) => {
+            Encoding::from_str(encoding).ok_or_else(|| Error(format!("unknown encoding {}", encoding)))?
+        }
+        None => parent_encoding,
+    };
+
+    let content_length = header.content_length;
+
+    // Unlike `DiffxParser`, the incremental parser does not yet decode
+    // compressed (`content-encoding`) payloads: doing so would require
+    // detecting truncated compressed streams as `Partial` too, which
+    // `flate2`'s decoders don't expose. `identity` (the default) is
+    // unaffected since it does no decoding at all.
+    let content_encoding = match header.options.get("content-encoding") {
+        Some(content_encoding) => {
+            match ContentEncoding::from_str(content_encoding) {
+                Some(ContentEncoding::Identity) => ContentEncoding::Identity,
+                Some(_) => {
+                    return Err(Error(format!("content-encoding {} is not yet supported by the incremental parser",
+                                              content_encoding)));
+                }
+                None => return Err(Error(format!("unknown content-encoding {}", content_encoding))),
+            }
+        }
+        None => ContentEncoding::Identity,
+    };
+
+    let rest = &input[header_len..];
+    let (content, content_len) =
+        match parse_section_content_partial(rest, depth, content_length, encoding, at_eof)? {
+            Status::Complete { value, consumed_bytes } => (value, consumed_bytes),
+            Status::Partial => return Ok(Status::Partial),
+        };
+
+    Ok(Status::Complete {
+        value: (header.title, Section {
+            encoding: encoding,
+            version: header.version,
+            content_length: header.content_length,
+            options: header.options,
+            content_encoding: content_encoding,
+            decoded_length: content_length.unwrap_or(0),
+            content: content,
+        }),
+        consumed_bytes: header_len + content_len,
+    })
+}
+
+fn parse_section_content_partial<'a>(input: &'a [u8],
+                                      depth: usize,
+                                      content_length: Option<usize>,
+                                      encoding: Encoding,
+                                      at_eof: bool)
+                                      -> Result<Status<SectionContent<'a>>, Error> {
+    if let Some(content_length) = content_length {
+        let required_len = content_length.checked_add(1)
+            .ok_or_else(|| Error("content-length is too large".into()))?;
+        if input.len() < required_len {
+            if at_eof {
+                return Err(Error("input ended before the declared content-length was satisfied".into()));
+            }
+            return Ok(Status::Partial);
+        }
+
+        let (data, rest) = input.split_at(content_length);
+        if rest[0] != b'\n' {
+            return Err(Error("section content was not followed by a blank line".into()));
+        }
+
+        let content = match encoding {
+            Encoding::Binary => SectionContent::RawData(data),
+            Encoding::Utf8 => {
+                SectionContent::EncodedData(str::from_utf8(data)
+                    .map_err(|_| Error("section content was not valid UTF-8".into()))?)
+            }
+        };
+
+        return Ok(Status::Complete {
+            value: content,
+            consumed_bytes: required_len,
+        });
+    }
+
+    let mut consumed = 0;
+    let mut children = HashMap::new();
+
+    loop {
+        match peek_header_depth(&input[consumed..])? {
+            HeaderPeek::NoMoreInput => {
+                // `at_eof` is the only way we know there genuinely are no
+                // more sibling headers coming; otherwise this is
+                // indistinguishable from "the next header just hasn't
+                // arrived yet".
+                if at_eof {
+                    break;
+                }
+                return Ok(Status::Partial);
+            }
+            HeaderPeek::Incomplete => {
+                if at_eof {
+                    return Err(Error("input ended in the middle of a section header".into()));
+                }
+                return Ok(Status::Partial);
+            }
+            HeaderPeek::Depth(child_depth) if child_depth < depth + 1 => break,
+            HeaderPeek::Depth(child_depth) if child_depth > depth + 1 => {
+                return Err(Error(format!("expected section with depth {}, found {}", depth + 1, child_depth)));
+            }
+            HeaderPeek::Depth(_) => {
+                match parse_section_partial(&input[consumed..], depth + 1, encoding, at_eof)? {
+                    Status::Complete { value: (title, section), consumed_bytes } => {
+                        children.insert(title, section);
+                        consumed += consumed_bytes;
+                    }
+                    Status::Partial => return Ok(Status::Partial),
+                }
+            }
+        }
+    }
+
+    if children.is_empty() {
+        return Err(Error("section had neither content-length nor child sections".into()));
+    }
+
+    Ok(Status::Complete {
+        value: SectionContent::ChildSections(children),
+        consumed_bytes: consumed,
+    })
+}
+
+// The outcome of a speculative look at the next section header in `input`.
+enum HeaderPeek {
+    /// `input` is empty: either more bytes may still arrive, or (if
+    /// `at_eof`) there simply are no more sibling sections.
+    NoMoreInput,
+    /// Some bytes are present, but not enough to tell the header's depth
+    /// apart from a truncated one.
+    Incomplete,
+    /// Enough of the header was present to read off its depth.
+    Depth(usize),
+}
+
+// Determine the depth of the next section header in `input` without
+// requiring the whole header line to be present yet.
+fn peek_header_depth(input: &[u8]) -> Result<HeaderPeek, Error> {
+    if input.is_empty() {
+        return Ok(HeaderPeek::NoMoreInput);
+    }
+    if input[0] != b'#' {
+        return Err(Error("expected '#' at start of section header".into()));
+    }
+
+    let mut depth = 0;
+    loop {
+        match input.get(1 + depth) {
+            None => return Ok(HeaderPeek::Incomplete),
+            Some(&b'.') => depth += 1,
+            Some(_) => return Ok(HeaderPeek::Depth(depth)),
+        }
+    }
+}
+
+struct HeaderInfo<'a> {
+    depth: usize,
+    title: &'a str,
+    version: Option<&'a str>,
+    encoding: Option<&'a str>,
+    content_length: Option<usize>,
+    options: Options<'a>,
+}
+
+// Parse a single section header line (`#...title: options\n`), returning
+// `Status::Partial` if `input` does not yet contain a terminating `\n` and
+// `at_eof` is `false`.
+fn parse_header_partial<'a>(input: &'a [u8], at_eof: bool) -> Result<Status<HeaderInfo<'a>>, Error> {
+    let line_len = match memchr(b'\n', input) {
+        Some(i) => i,
+        None => {
+            if at_eof {
+                return Err(Error("input ended before the section header's terminating newline".into()));
+            }
+            return Ok(Status::Partial);
+        }
+    };
+    let line = &input[..line_len];
+
+    if line.first() != Some(&b'#') {
+        return Err(Error("expected '#' at start of section header".into()));
+    }
+    let header = grammar::scan_header_line(&line[1..]).map_err(|e| Error(e.into()))?;
+    let depth = header.depth;
+    let title = header.title;
+
+    let raw_options = parse_option_list(grammar::trim(header.rest))?;
+
+    // `version`, `encoding`, and `content-length` are common enough to be
+    // worth parsing eagerly into their own fields; everything else is left
+    // in `options` for callers to look up by name.
+    let mut version = None;
+    let mut encoding = None;
+    let mut content_length = None;
+    let mut options = Options::new();
+
+    for (key, value) in raw_options.iter() {
+        match key {
+            "version" => version = Some(value),
+            "encoding" => encoding = Some(value),
+            "content-length" => {
+                content_length = Some(value.parse::<usize>()
+                    .map_err(|_| Error(format!("invalid content-length {}", value)))?);
+            }
+            _ => options.push(key, value),
+        }
+    }
+
+    Ok(Status::Complete {
+        value: HeaderInfo {
+            depth: depth,
+            title: title,
+            version: version,
+            encoding: encoding,
+            content_length: content_length,
+            options: options,
+        },
+        consumed_bytes: line_len + 1,
+    })
+}
+
+fn parse_option_list<'a>(input: &'a [u8]) -> Result<Options<'a>, Error> {
+    let mut options = Options::new();
+    for pair in grammar::option_pairs(input) {
+        let (key, value) = pair.map_err(|_| Error("malformed option".into()))?;
+        options.push(key, value);
+    }
+    Ok(options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partial_header_truncated() {
+        assert_eq!(parse_partial(b"#diffx: version=1.0", false), Ok(Status::Partial));
+    }
+
+    #[test]
+    fn test_partial_content_truncated() {
+        let input = b"#diffx: content-length=16,encoding=utf-8\nGoodbye, wor";
+        assert_eq!(parse_partial(input, false), Ok(Status::Partial));
+    }
+
+    #[test]
+    fn test_partial_content_missing_trailing_newline() {
+        let input = b"#diffx: content-length=5,encoding=utf-8\nhello";
+        assert_eq!(parse_partial(input, false), Ok(Status::Partial));
+    }
+
+    #[test]
+    fn test_complete_leaf_section() {
+        let input = b"#diffx: content-length=5,encoding=utf-8\nhello\n";
+        match parse_partial(input, false).unwrap() {
+            Status::Complete { value: (title, section), consumed_bytes } => {
+                assert_eq!(title, "diffx");
+                assert_eq!(section.content, SectionContent::EncodedData("hello"));
+                assert_eq!(consumed_bytes, input.len());
+            }
+            other => panic!("expected Complete, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_complete_leaf_section_reports_trailing_bytes_unconsumed() {
+        let input = b"#diffx: content-length=5,encoding=utf-8\nhello\nmore data after";
+        match parse_partial(input, false).unwrap() {
+            Status::Complete { consumed_bytes, .. } => {
+                assert_eq!(consumed_bytes, "#diffx: content-length=5,encoding=utf-8\nhello\n".len());
+            }
+            other => panic!("expected Complete, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_malformed_is_an_error_not_partial() {
+        let input = b"#diffx: content-length=not-a-number\nhello\n";
+        assert!(parse_partial(input, false).is_err());
+    }
+
+    #[test]
+    fn test_partial_nested_children() {
+        let input = b"\
+#diffx: encoding=utf-8
+#.foo: content-length=5
+hello
+#.bar: content-length=5
+worl";
+        assert_eq!(parse_partial(input, false), Ok(Status::Partial));
+    }
+
+    #[test]
+    fn test_nested_children_remain_partial_before_eof() {
+        // A fully well-formed document is still `Partial` when the caller
+        // hasn't said the stream ended: more sibling sections could still
+        // arrive.
+        let input = b"#diffx: encoding=utf-8\n#.foo: content-length=5\nhello\n";
+        assert_eq!(parse_partial(input, false), Ok(Status::Partial));
+    }
+
+    #[test]
+    fn test_nested_children_complete_at_eof() {
+        let input = b"#diffx: encoding=utf-8\n#.foo: content-length=5\nhello\n";
+        match parse_partial(input, true).unwrap() {
+            Status::Complete { value: (title, section), consumed_bytes } => {
+                assert_eq!(title, "diffx");
+                match section.content {
+                    SectionContent::ChildSections(ref children) => {
+                        assert_eq!(children.len(), 1);
+                        assert!(children.contains_key("foo"));
+                    }
+                    ref other => panic!("expected ChildSections, got {:?}", other),
+                }
+                assert_eq!(consumed_bytes, input.len());
+            }
+            other => panic!("expected Complete, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_truncated_content_is_an_error_at_eof() {
+        let input = b"#diffx: content-length=16,encoding=utf-8\nGoodbye, wor";
+        assert!(parse_partial(input, true).is_err());
+    }
+
+    #[test]
+    fn test_truncated_header_is_an_error_at_eof() {
+        assert!(parse_partial(b"#diffx: version=1.0", true).is_err());
+    }
+}
@@ -1,15 +1,29 @@
-extern crate combine;
 #[macro_use]
 extern crate maplit;
+extern crate memchr;
+#[cfg(feature = "flate2")]
+extern crate flate2;
 
 use std::collections::HashMap;
-use std::marker::PhantomData;
+use std::error::Error as StdError;
+use std::fmt;
+#[cfg(feature = "flate2")]
+use std::io::Read;
 use std::str;
 
-use combine::byte::*;
-use combine::combinator::*;
-use combine::primitives::{Consumed, Error, ParseError, ParseResult, Parser, RangeStream};
-use combine::range::*;
+use memchr::memchr;
+
+mod grammar;
+use grammar::{is_option_char, peek_header_depth};
+
+mod writer;
+pub use writer::{DocumentBuilder, SectionBuilder};
+
+mod editor;
+pub use editor::{DocumentEditor, EditSection, EditorError};
+
+mod incremental;
+pub use incremental::{parse_partial, parse_section_partial, Error as PartialParseError, Status};
 
 
 #[derive(Debug, PartialEq, Eq)]
@@ -23,8 +37,41 @@ pub struct Section<'a> {
     /// [binary]: enum.Encoding.html#Binary.v
     pub encoding: Encoding,
 
-    /// The options of this section.
-    pub options: HashMap<&'a str, &'a str>,
+    /// This section's `version` option, if any.
+    pub version: Option<&'a str>,
+
+    /// This section's `content-length` option, parsed as an integer, if
+    /// it has one.
+    ///
+    /// This is the on-disk length, i.e. before `content_encoding`
+    /// decompression; see [`decoded_length`][Section::decoded_length] for
+    /// the length after decompression.
+    pub content_length: Option<usize>,
+
+    /// The remaining options of this section, in declaration order.
+    ///
+    /// `version`, `encoding`, and `content-length` are not included here,
+    /// since they are parsed eagerly into the fields above.
+    pub options: Options<'a>,
+
+    /// How this section's on-disk content is compressed, as declared by a
+    /// `content-encoding` option.
+    ///
+    /// Defaults to [`ContentEncoding::Identity`][identity] when the section
+    /// has no `content-encoding` option, or has child sections rather than
+    /// data.
+    ///
+    /// [identity]: enum.ContentEncoding.html#Identity.v
+    pub content_encoding: ContentEncoding,
+
+    /// The length, in bytes, of this section's content after decompression.
+    ///
+    /// Equal to the section's `content-length` option when `content_encoding`
+    /// is [`ContentEncoding::Identity`][identity]; `0` for a section with
+    /// child sections rather than data.
+    ///
+    /// [identity]: enum.ContentEncoding.html#Identity.v
+    pub decoded_length: usize,
 
     /// The content of this section.
     ///
@@ -47,10 +94,66 @@ pub enum SectionContent<'a> {
 
     /// Raw binary data.
     RawData(&'a [u8]),
+
+    /// Encoded data decompressed from a `content-encoding` payload.
+    ///
+    /// Owned, since decompression cannot produce a slice borrowed from the
+    /// original input.
+    DecodedEncodedData(String),
+
+    /// Raw binary data decompressed from a `content-encoding` payload.
+    ///
+    /// Owned, since decompression cannot produce a slice borrowed from the
+    /// original input.
+    DecodedRawData(Vec<u8>),
 }
 
 use SectionContent::*;
 
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// An ordered collection of a section's options.
+///
+/// Unlike a `HashMap`, this preserves declaration order and repeated keys,
+/// similar to the `Dictionary` type of RFC 8941 ("Structured Field
+/// Values"), which is likewise a sequence of key-value pairs rather than a
+/// map.
+pub struct Options<'a>(Vec<(&'a str, &'a str)>);
+
+impl<'a> Options<'a> {
+    fn new() -> Options<'a> {
+        Options(Vec::new())
+    }
+
+    fn push(&mut self, key: &'a str, value: &'a str) {
+        self.0.push((key, value));
+    }
+
+    fn contains_key(&self, key: &str) -> bool {
+        self.0.iter().any(|&(k, _)| k == key)
+    }
+
+    /// The value of the last option with the given key, if any.
+    ///
+    /// The last declaration wins, matching how the eagerly parsed
+    /// `version`/`encoding`/`content-length` fields resolve duplicates
+    /// under lenient (non-strict) [`ParseOptions`]. Use
+    /// [`get_all`][Options::get_all] to see every value for a key that was
+    /// declared more than once.
+    pub fn get(&self, key: &str) -> Option<&'a str> {
+        self.0.iter().rev().find(|&&(k, _)| k == key).map(|&(_, v)| v)
+    }
+
+    /// All values for the given key, in declaration order.
+    pub fn get_all<'b>(&'b self, key: &'b str) -> impl Iterator<Item = &'a str> + 'b {
+        self.0.iter().filter(move |&&(k, _)| k == key).map(|&(_, v)| v)
+    }
+
+    /// All of this section's options, in declaration order.
+    pub fn iter<'b>(&'b self) -> impl Iterator<Item = (&'a str, &'a str)> + 'b {
+        self.0.iter().cloned()
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 /// An enumeration representing possible encoding of DiffX sections.
 pub enum Encoding {
@@ -75,170 +178,553 @@ impl Encoding {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// How a section's on-disk content is compressed, as declared by a
+/// `content-encoding` option. Modeled on actix-web's `ContentEncoding`.
+///
+/// `content-length` always refers to the number of on-disk bytes, i.e. the
+/// compressed size when this is not `Identity`; see
+/// [`Section::decoded_length`][decoded_length] for the size after
+/// decompression.
+///
+/// Decoding `Gzip` and `Deflate` requires building with the `flate2` cargo
+/// feature; without it, a section declaring either is a parse error.
+///
+/// [decoded_length]: struct.Section.html#structfield.decoded_length
+pub enum ContentEncoding {
+    /// The payload is stored as-is.
+    Identity,
+
+    /// The payload is gzip-compressed.
+    Gzip,
+
+    /// The payload is raw DEFLATE-compressed.
+    Deflate,
+}
+
+impl ContentEncoding {
+    fn from_str(s: &str) -> Option<ContentEncoding> {
+        match s {
+            "identity" => Some(ContentEncoding::Identity),
+            "gzip" => Some(ContentEncoding::Gzip),
+            "deflate" => Some(ContentEncoding::Deflate),
+            _ => None,
+        }
+    }
+}
+
+fn content_encoding_str(content_encoding: ContentEncoding) -> &'static str {
+    match content_encoding {
+        ContentEncoding::Identity => "identity",
+        ContentEncoding::Gzip => "gzip",
+        ContentEncoding::Deflate => "deflate",
+    }
+}
+
+/// An error encountered while parsing a DiffX document.
 #[derive(Debug, PartialEq, Eq)]
-struct SectionHeader<'a> {
-    depth: usize,
-    title: &'a str,
-    options: HashMap<&'a str, &'a str>,
+pub struct ParseError(String);
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
 }
 
-fn is_option_char(c: u8) -> bool {
-    match c {
-        b'a'...b'z' | b'A'...b'Z' | b'0'...b'9' | b'-' | b'_' | b'.' => true,
-        _ => false,
+impl StdError for ParseError {
+    fn description(&self) -> &str {
+        &self.0
     }
 }
 
-fn is_section_header_char(c: u8) -> bool {
-    match c {
-        b'a'...b'z' | b'A'...b'Z' | b'-' => true,
-        _ => false,
+// Decode a leaf section's on-disk bytes (`bs`, whose length is the
+// section's `content-length`) by first undoing `content_encoding`
+// compression, then applying `encoding`. Returns the decoded content
+// alongside its length in bytes.
+fn decode_section_content<'a>(bs: &'a [u8],
+                               content_encoding: ContentEncoding,
+                               encoding: Encoding,
+                               max_decoded_length: Option<usize>)
+                               -> Result<(SectionContent<'a>, usize), ParseError> {
+    match content_encoding {
+        ContentEncoding::Identity => {
+            let content = match encoding {
+                Encoding::Binary => RawData(bs),
+                Encoding::Utf8 => {
+                    EncodedData(try!(str::from_utf8(bs).map_err(|e| ParseError(e.to_string()))))
+                }
+            };
+            Ok((content, bs.len()))
+        }
+        ContentEncoding::Gzip | ContentEncoding::Deflate => {
+            decode_compressed(bs, content_encoding, encoding, max_decoded_length)
+        }
     }
 }
 
-struct DiffxParser<I>(PhantomData<I>);
-impl<'a, I> DiffxParser<I>
-    where I: RangeStream<Item = u8, Range = &'a [u8]>
-{
+#[cfg(feature = "flate2")]
+fn decode_compressed<'a>(bs: &[u8],
+                         content_encoding: ContentEncoding,
+                         encoding: Encoding,
+                         max_decoded_length: Option<usize>)
+                         -> Result<(SectionContent<'a>, usize), ParseError> {
+    let mut buf = Vec::new();
+    // Cap the number of bytes actually read out of the decoder at one more
+    // than the limit, so an over-limit payload is detected without having
+    // to materialize it in full first.
+    let read_result = match (content_encoding, max_decoded_length) {
+        (ContentEncoding::Gzip, Some(max)) => {
+            flate2::read::GzDecoder::new(bs).take(bound_reader_limit(max)).read_to_end(&mut buf)
+        }
+        (ContentEncoding::Gzip, None) => flate2::read::GzDecoder::new(bs).read_to_end(&mut buf),
+        (ContentEncoding::Deflate, Some(max)) => {
+            flate2::read::DeflateDecoder::new(bs).take(bound_reader_limit(max)).read_to_end(&mut buf)
+        }
+        (ContentEncoding::Deflate, None) => flate2::read::DeflateDecoder::new(bs).read_to_end(&mut buf),
+        (ContentEncoding::Identity, _) => unreachable!(),
+    };
+    try!(read_result.map_err(|e| {
+        ParseError(format!("failed to decompress {} content: {}",
+                            content_encoding_str(content_encoding),
+                            e))
+    }));
+
+    if let Some(max) = max_decoded_length {
+        if buf.len() > max {
+            return Err(ParseError(format!("decompressed {} content exceeds max_decoded_length of {}",
+                                           content_encoding_str(content_encoding),
+                                           max)));
+        }
+    }
+
+    let decoded_length = buf.len();
+    let content = match encoding {
+        Encoding::Binary => DecodedRawData(buf),
+        Encoding::Utf8 => {
+            DecodedEncodedData(try!(String::from_utf8(buf).map_err(|e| ParseError(e.to_string()))))
+        }
+    };
+    Ok((content, decoded_length))
+}
+
+// `Read::take` takes a `u64`; one more than `max_decoded_length` so an
+// exactly-at-the-limit payload is not falsely flagged as over it, saturating
+// rather than overflowing if `max_decoded_length` is `usize::MAX`.
+#[cfg(feature = "flate2")]
+fn bound_reader_limit(max_decoded_length: usize) -> u64 {
+    (max_decoded_length as u64).saturating_add(1)
+}
+
+#[cfg(not(feature = "flate2"))]
+fn decode_compressed<'a>(_bs: &[u8],
+                         content_encoding: ContentEncoding,
+                         _encoding: Encoding,
+                         _max_decoded_length: Option<usize>)
+                         -> Result<(SectionContent<'a>, usize), ParseError> {
+    Err(ParseError(format!("content-encoding {} requires building with the flate2 feature",
+                            content_encoding_str(content_encoding))))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Configuration for [`DiffxParser::parse_with_options`][parse_with_options].
+///
+/// By default all limits are disabled and parsing is lenient: a duplicate
+/// option key in a header is last-wins, and an unrecognized `encoding`
+/// falls back to the parent section's encoding. Use `strict` to reject
+/// both of those instead, and the `max_*` fields to bound the resources an
+/// untrusted document can make the parser spend.
+///
+/// [parse_with_options]: struct.DiffxParser.html#method.parse_with_options
+pub struct ParseOptions {
+    /// Reject duplicate option keys within a single header, and make an
+    /// unrecognized `encoding` value a hard error instead of falling back
+    /// to the parent encoding.
+    pub strict: bool,
+
+    /// The maximum nesting depth of sections. `None` means unlimited.
+    pub max_depth: Option<usize>,
+
+    /// The maximum number of options permitted in a single section header.
+    /// `None` means unlimited.
+    pub max_options: Option<usize>,
+
+    /// The maximum number of sections permitted in an entire document.
+    /// `None` means unlimited.
+    pub max_sections: Option<usize>,
+
+    /// The maximum size, in bytes, a `content-encoding`-compressed section's
+    /// payload is allowed to decompress to. `None` means unlimited.
+    ///
+    /// Unlike the other `max_*` fields, this bounds work done *after*
+    /// `content-length` framing has already been validated: a small
+    /// compressed payload can otherwise decompress to an arbitrary amount
+    /// of memory (a "decompression bomb") regardless of how conservatively
+    /// the other limits are set. Only takes effect when built with the
+    /// `flate2` feature; it has no effect on `identity`-encoded content,
+    /// which is never decompressed.
+    pub max_decoded_length: Option<usize>,
+
+    // Hard-error on an unrecognized `encoding` independently of `strict`.
+    //
+    // This only exists so `legacy_options` can reproduce the original,
+    // pre-`ParseOptions` behavior (duplicate keys are last-wins, but a
+    // bad `encoding` is always an error) without `strict` meaning two
+    // different things to callers of `parse_with_options`. Not public:
+    // the public API only ever needs `strict` to mean "both".
+    unrecognized_encoding_is_error: bool,
+}
+
+impl Default for ParseOptions {
+    fn default() -> ParseOptions {
+        ParseOptions {
+            strict: false,
+            max_depth: None,
+            max_options: None,
+            max_sections: None,
+            max_decoded_length: None,
+            unrecognized_encoding_is_error: false,
+        }
+    }
+}
+
+// The options used by the legacy, pre-`ParseOptions` entry points
+// (`DiffxParser::section` and friends) so their behavior does not change:
+// no resource limits, duplicate option keys are last-wins, but an
+// unrecognized `encoding` is still a hard error.
+fn legacy_options() -> ParseOptions {
+    ParseOptions { unrecognized_encoding_is_error: true, ..ParseOptions::default() }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct SectionHeader<'a> {
+    depth: usize,
+    title: &'a str,
+    version: Option<&'a str>,
+    encoding: Option<&'a str>,
+    content_length: Option<usize>,
+    options: Options<'a>,
+}
+
+/// The DiffX parser.
+///
+/// Unlike the streaming entry points in [`incremental`][super::incremental],
+/// these methods require `input` to already hold the entire section (or
+/// document) being parsed, and return a hard [`ParseError`] rather than
+/// reporting truncated input specially.
+///
+/// Internally, this scans `input` with a single left-to-right byte cursor:
+/// header lines are found by locating their terminating `\n` with
+/// [`memchr`](https://docs.rs/memchr) in one pass (rather than walking a
+/// parser-combinator pipeline byte-by-byte), and option lists are written
+/// straight into the result [`Options`] instead of being collected into an
+/// intermediate `Vec` first. This keeps parsing a multi-megabyte document to
+/// a single pass with no per-section heap allocation beyond the `Section`
+/// tree itself.
+pub struct DiffxParser;
+
+impl DiffxParser {
     // Parse an option key or value.
-    fn option_str(input: I) -> ParseResult<&'a str, I> {
-        // The call to str::from_utf8_unchecked is safe due to is_option_char
-        // only accepting a limited subset of ASCII.
-        take_while1(is_option_char)
-            .map(|s| unsafe { str::from_utf8_unchecked(s) })
-            .parse_stream(input)
+    fn option_str(input: &[u8]) -> Result<(&str, &[u8]), ParseError> {
+        let len = input.iter().take_while(|&&c| is_option_char(c)).count();
+        if len == 0 {
+            return Err(ParseError("expected an option key or value".into()));
+        }
+
+        // Safe due to is_option_char only accepting a limited subset of ASCII.
+        let s = unsafe { str::from_utf8_unchecked(&input[..len]) };
+        Ok((s, &input[len..]))
     }
 
     // Parse an option.
     //
-    // Options are key-vaue pairs separated by `=`.
-    fn option(input: I) -> ParseResult<(&'a str, &'a str), I> {
-        (parser(DiffxParser::<I>::option_str),
-         byte('=' as u8).with(parser(DiffxParser::<I>::option_str)))
-            .parse_stream(input)
+    // Options are key-value pairs separated by `=`.
+    fn option(input: &[u8]) -> Result<((&str, &str), &[u8]), ParseError> {
+        let (key, rest) = try!(DiffxParser::option_str(input));
+
+        let rest = match rest.first() {
+            Some(&b'=') => &rest[1..],
+            _ => return Err(ParseError("expected '=' after option key".into())),
+        };
+
+        let (value, rest) = try!(DiffxParser::option_str(rest));
+        Ok(((key, value), rest))
     }
 
     // Parse an option list.
     //
     // Option lists are a list of options separated by `,`. The result is
-    // collected into a HashMap for convenience.
-    fn option_list(input: I) -> ParseResult<HashMap<&'a str, &'a str>, I> {
-        sep_by(parser(DiffxParser::<I>::option), byte(',' as u8))
-            .map(|tuples: Vec<_>| tuples.into_iter().collect())
-            .parse_stream(input)
+    // collected into an `Options`, in declaration order, for convenience.
+    fn option_list(input: &[u8]) -> Result<(Options, &[u8]), ParseError> {
+        DiffxParser::option_list_with_options(input, legacy_options())
+    }
+
+    // Like `option_list`, but enforces `options.max_options` and, in strict
+    // mode, rejects duplicate keys instead of letting the last one win.
+    //
+    // Options are pushed onto the result `Options` as they are parsed,
+    // rather than first being collected into a separate `Vec` of pairs.
+    fn option_list_with_options(input: &[u8],
+                                options: ParseOptions)
+                                -> Result<(Options, &[u8]), ParseError> {
+        let mut pairs = Options::new();
+        let mut count = 0;
+        let mut rest = input;
+
+        loop {
+            let ((key, value), new_rest) = try!(DiffxParser::option(rest));
+            rest = new_rest;
+
+            count += 1;
+            if let Some(max_options) = options.max_options {
+                if count > max_options {
+                    return Err(ParseError(format!("more than {} options", max_options)));
+                }
+            }
+
+            if options.strict && pairs.contains_key(key) {
+                return Err(ParseError(["duplicate option", key].join(" ")));
+            }
+            pairs.push(key, value);
+
+            match rest.first() {
+                Some(&b',') => rest = &rest[1..],
+                _ => break,
+            }
+        }
+
+        Ok((pairs, rest))
     }
 
     // Parse a section header.
-    fn section_header(input: I) -> ParseResult<SectionHeader<'a>, I> {
-        let depth = take_while(|c| c == b'.').map(|xs: &[_]| xs.len());
-
-        // Again, the call str::from_utf8_unchecked is safe due to
-        // is_section_header_char only accepting a limited subset of ASCII.
-        let title = take_while(is_section_header_char)
-            .map(|s| unsafe { str::from_utf8_unchecked(s) });
-
-        let option_list = skip_many1(byte(b' ')).with(parser(DiffxParser::<I>::option_list));
-
-        byte(b'#')
-            .with((depth, title.skip(byte(b':')), optional(option_list)))
-            .skip(skip_many(byte(b' ')))
-            .skip(byte(b'\n'))
-            .map(|(depth, title, maybe_options)| {
-                SectionHeader {
-                    depth: depth,
-                    title: title,
-                    options: maybe_options.unwrap_or_else(HashMap::new),
+    fn section_header(input: &[u8]) -> Result<(SectionHeader, &[u8]), ParseError> {
+        DiffxParser::section_header_with_options(input, legacy_options())
+    }
+
+    // Like `section_header`, but threads `options` through to the option list.
+    fn section_header_with_options(input: &[u8],
+                                    options: ParseOptions)
+                                    -> Result<(SectionHeader, &[u8]), ParseError> {
+        let body = match input.first() {
+            Some(&b'#') => &input[1..],
+            _ => return Err(ParseError("expected '#' at start of section header".into())),
+        };
+
+        // Locate the header line's terminating newline with `memchr` in one
+        // pass, so the rest of this function parses within a bounded line
+        // instead of discovering the end of the header byte-by-byte.
+        let newline = match memchr(b'\n', body) {
+            Some(i) => i,
+            None => return Err(ParseError("expected newline at end of section header".into())),
+        };
+        let (line, rest) = body.split_at(newline);
+        let rest = &rest[1..];
+
+        let header = try!(grammar::scan_header_line(line).map_err(|e| ParseError(e.into())));
+        let depth = header.depth;
+        let title = header.title;
+        let line = header.rest;
+
+        let space_len = line.iter().take_while(|&&c| c == b' ').count();
+        let after_spaces = &line[space_len..];
+        let (mut header_options, line) = if space_len > 0 &&
+                                      after_spaces.first().map_or(false, |&c| is_option_char(c)) {
+            try!(DiffxParser::option_list_with_options(after_spaces, options))
+        } else {
+            (Options::new(), line)
+        };
+
+        let trailing_spaces = line.iter().take_while(|&&c| c == b' ').count();
+        let line = &line[trailing_spaces..];
+        if !line.is_empty() {
+            return Err(ParseError("expected newline at end of section header".into()));
+        }
+
+        // `version`, `encoding`, and `content-length` are common enough to
+        // be worth parsing eagerly into their own fields; everything else
+        // is left in `header_options` for callers to look up by name.
+        let mut version = None;
+        let mut encoding = None;
+        let mut content_length = None;
+
+        for (key, value) in header_options.iter() {
+            match key {
+                "version" => version = Some(value),
+                "encoding" => encoding = Some(value),
+                "content-length" => {
+                    content_length = Some(match value.parse() {
+                        Ok(content_length) => content_length,
+                        Err(_) => return Err(ParseError(["content-length", value].join(" "))),
+                    });
                 }
-            })
-            .parse_stream(input)
+                _ => {}
+            }
+        }
+        header_options.0.retain(|&(key, _)| key != "version" && key != "encoding" && key != "content-length");
+
+        Ok((SectionHeader {
+            depth: depth,
+            title: title,
+            version: version,
+            encoding: encoding,
+            content_length: content_length,
+            options: header_options,
+        },
+            rest))
     }
 
     // Return a parser that can parse a section of a given depth.
     //
     // The `parent_encoding` will be used as the encoding of the section if it
     // does not specify one.
-    fn section(depth: usize,
-               parent_encoding: Encoding)
-               -> Box<Parser<Input = I, Output = (&'a str, Section<'a>)> + 'a>
-        where I: 'a
-    {
-        parser(move |input: I| {
-                let start = input.position();
-                let (header, input) = try!(parser(DiffxParser::<I>::section_header)
-                    .parse_stream(input));
-
-                if header.depth != depth {
-                    return Err(Consumed::Consumed(ParseError::new(start, Error::Expected(
-                               format!("section with depth {}", depth).into()))));
+    pub fn section(input: &[u8],
+                    depth: usize,
+                    parent_encoding: Encoding)
+                    -> Result<((&str, Section), &[u8]), ParseError> {
+        DiffxParser::section_with_options(input, depth, parent_encoding, legacy_options(), &mut 0)
+    }
+
+    /// Parse an entire document from `input`, honoring `options` for
+    /// strictness and the `max_depth`/`max_options`/`max_sections` resource
+    /// limits.
+    ///
+    /// This is the entry point to use on untrusted input: the default
+    /// [`DiffxParser::section`][section] accepts arbitrarily deep nesting
+    /// and arbitrarily many options or sections.
+    ///
+    /// [section]: struct.DiffxParser.html#method.section
+    pub fn parse_with_options(input: &[u8],
+                               options: ParseOptions)
+                               -> Result<((&str, Section), &[u8]), ParseError> {
+        DiffxParser::section_with_options(input, 0, Encoding::Binary, options, &mut 0)
+    }
+
+    // Like `section`, but threads `options` and a shared section counter
+    // (for enforcing `max_sections` across the whole document) through the
+    // recursive descent into child sections.
+    fn section_with_options<'a>(input: &'a [u8],
+                                 depth: usize,
+                                 parent_encoding: Encoding,
+                                 options: ParseOptions,
+                                 section_count: &mut usize)
+                                 -> Result<((&'a str, Section<'a>), &'a [u8]), ParseError> {
+        if let Some(max_depth) = options.max_depth {
+            if depth > max_depth {
+                return Err(ParseError(format!("nesting deeper than max_depth of {}", max_depth)));
+            }
+        }
+
+        *section_count += 1;
+        if let Some(max_sections) = options.max_sections {
+            if *section_count > max_sections {
+                return Err(ParseError(format!("more than {} sections", max_sections)));
+            }
+        }
+
+        let (header, rest) = try!(DiffxParser::section_header_with_options(input, options));
+
+        if header.depth != depth {
+            return Err(ParseError(format!("expected section with depth {}, found {}", depth, header.depth)));
+        }
+
+        let encoding = match header.encoding {
+            Some(encoding) => {
+                match Encoding::from_str(encoding) {
+                    Some(encoding) => encoding,
+                    None if !options.strict && !options.unrecognized_encoding_is_error => parent_encoding,
+                    None => return Err(ParseError(["encoding", encoding].join(" "))),
                 }
+            }
+            None => parent_encoding,
+        };
 
-                let encoding = match header.options.get("encoding") {
-                    Some(encoding) => {
-                        match Encoding::from_str(encoding) {
-                            Some(encoding) => encoding,
-                            None => {
-                                let msg = ["encoding", encoding].join(" ");
-                                let err = Error::Unexpected(msg.into());
-                                return Err(Consumed::Consumed(ParseError::new(start, err)));
-                            }
-                        }
+        let ((content, content_encoding, decoded_length), rest) =
+            try!(DiffxParser::section_content_with_options(&header, encoding, rest, options, section_count));
+
+        Ok(((header.title,
+             Section {
+                 encoding: encoding,
+                 version: header.version,
+                 content_length: header.content_length,
+                 options: header.options,
+                 content_encoding: content_encoding,
+                 decoded_length: decoded_length,
+                 content: content,
+             }),
+            rest))
+    }
+
+    // Parse the content of a section, threading `options` and the shared
+    // section counter through to any child sections.
+    fn section_content_with_options<'a, 'b>
+        (section_header: &'b SectionHeader<'a>,
+         encoding: Encoding,
+         input: &'a [u8],
+         options: ParseOptions,
+         section_count: &mut usize)
+         -> Result<((SectionContent<'a>, ContentEncoding, usize), &'a [u8]), ParseError> {
+        let content_length = section_header.content_length;
+
+        let content_encoding = match section_header.options.get("content-encoding") {
+            Some(content_encoding) => {
+                match ContentEncoding::from_str(content_encoding) {
+                    Some(content_encoding) => content_encoding,
+                    None => return Err(ParseError(["content-encoding", content_encoding].join(" "))),
+                }
+            }
+            None => ContentEncoding::Identity,
+        };
+
+        if let Some(content_length) = content_length {
+            let required_len = match content_length.checked_add(1) {
+                Some(required_len) => required_len,
+                None => return Err(ParseError("content-length is too large".into())),
+            };
+            if input.len() < required_len {
+                return Err(ParseError("section content ended before its declared content-length".into()));
+            }
+
+            let (bs, rest) = input.split_at(content_length);
+            let rest = match rest.first() {
+                Some(&b'\n') => &rest[1..],
+                _ => return Err(ParseError("section content was not followed by a newline".into())),
+            };
+
+            let (content, decoded_length) =
+                try!(decode_section_content(bs, content_encoding, encoding, options.max_decoded_length));
+            Ok(((content, content_encoding, decoded_length), rest))
+        } else {
+            let child_depth = section_header.depth + 1;
+            let mut children = HashMap::new();
+            let mut rest = input;
+
+            loop {
+                match peek_header_depth(rest) {
+                    None => break,
+                    Some(depth) if depth < child_depth => break,
+                    Some(depth) if depth > child_depth => {
+                        return Err(ParseError(format!("expected section with depth {}, found {}",
+                                                        child_depth,
+                                                        depth)));
                     }
-                    None => parent_encoding,
-                };
-
-                let (content, input) = try!(DiffxParser::<I>::section_content(&header, encoding)
-                    .parse_stream(input.into_inner()));
-
-                Ok(((header.title,
-                     Section {
-                         encoding: encoding,
-                         options: header.options,
-                         content: content,
-                     }),
-                    input))
-            })
-            .boxed()
-    }
-
-    // Return a parser that will parse the content of a section given its header.
-    fn section_content<'b>(section_header: &'b SectionHeader<'a>,
-                           encoding: Encoding)
-                           -> Box<Parser<Input = I, Output = (SectionContent<'a>)> + 'b>
-        where I: 'a
-    {
-        parser(move |input: I| {
-                let start = input.position();
-                let content_length = match section_header.options.get("content-length") {
-                    Some(content_length) => {
-                        match content_length.parse() {
-                            Ok(content_length) => Some(content_length),
-                            Err(_) => {
-                                let msg = ["content-length", content_length].join(" ");
-                                let err = Error::Unexpected(msg.into());
-                                return Err(Consumed::Consumed(ParseError::new(start, err)));
-                            }
-                        }
+                    Some(_) => {
+                        let ((title, section), new_rest) =
+                            try!(DiffxParser::section_with_options(rest,
+                                                                    child_depth,
+                                                                    encoding,
+                                                                    options,
+                                                                    section_count));
+                        children.insert(title, section);
+                        rest = new_rest;
                     }
-                    None => None,
-                };
-
-                let (content, input) = try!(if let Some(content_length) = content_length {
-                    take(content_length)
-                        .and_then(|bs| match encoding {
-                            Encoding::Binary => Ok(RawData(bs)),
-                            Encoding::Utf8 => str::from_utf8(bs).map(EncodedData),
-                        })
-                        .skip(byte(b'\n'))
-                        .parse_stream(input)
-                } else {
-                    many1(try(DiffxParser::<I>::section(section_header.depth + 1, encoding)))
-                        .map(ChildSections)
-                        .parse_stream(input)
-                });
-
-                Ok((content, input))
-            })
-            .boxed()
+                }
+            }
+
+            if children.is_empty() {
+                return Err(ParseError("section had neither content-length nor child sections".into()));
+            }
+
+            Ok(((ChildSections(children), ContentEncoding::Identity, 0), rest))
+        }
     }
 }
 
@@ -248,83 +734,90 @@ mod tests {
 
     #[test]
     fn test_option() {
-        assert_eq!(parser(DiffxParser::option).parse(&b"foo=bar"[..]),
+        assert_eq!(DiffxParser::option(&b"foo=bar"[..]),
                    Ok((("foo", "bar"), &b""[..])));
 
-        assert_eq!(parser(DiffxParser::option).parse(&b"encoding=utf-8"[..]),
+        assert_eq!(DiffxParser::option(&b"encoding=utf-8"[..]),
                    Ok((("encoding", "utf-8"), &b""[..])));
 
-        assert_eq!(parser(DiffxParser::option).parse(&b"version=1.0"[..]),
+        assert_eq!(DiffxParser::option(&b"version=1.0"[..]),
                    Ok((("version", "1.0"), &b""[..])));
     }
 
     #[test]
     fn test_option_list() {
-        assert_eq!(parser(DiffxParser::option_list).parse(&b"foo=bar"[..]),
-                   Ok((hashmap!{ "foo" => "bar" }, &b""[..])));
+        assert_eq!(DiffxParser::option_list(&b"foo=bar"[..]),
+                   Ok((Options(vec![("foo", "bar")]), &b""[..])));
 
-        assert_eq!(parser(DiffxParser::option_list).parse(&b"encoding=utf-8,version=1.0"[..]),
-                   Ok((hashmap!{ "encoding" => "utf-8", "version" => "1.0" }, &b""[..])));
+        assert_eq!(DiffxParser::option_list(&b"encoding=utf-8,version=1.0"[..]),
+                   Ok((Options(vec![("encoding", "utf-8"), ("version", "1.0")]), &b""[..])));
     }
 
     #[test]
     fn test_section_header() {
-        assert_eq!(parser(DiffxParser::section_header)
-                       .parse(&b"#diffx: version=1.0,encoding=utf-8\n"[..]),
+        assert_eq!(DiffxParser::section_header(&b"#diffx: version=1.0,encoding=utf-8\n"[..]),
                    Ok((SectionHeader {
                            depth: 0,
                            title: "diffx",
-                           options: hashmap!{
-                               "version" => "1.0",
-                               "encoding" => "utf-8",
-                           },
+                           version: Some("1.0"),
+                           encoding: Some("utf-8"),
+                           content_length: None,
+                           options: Options(vec![]),
                        },
                        &b""[..])));
 
-        assert_eq!(parser(DiffxParser::section_header)
-                       .parse(&b"#..sub-section: content-length=128\n"[..]),
+        assert_eq!(DiffxParser::section_header(&b"#..sub-section: content-length=128\n"[..]),
                    Ok((SectionHeader {
                            depth: 2,
                            title: "sub-section",
-                           options: hashmap!{ "content-length" => "128" },
+                           version: None,
+                           encoding: None,
+                           content_length: Some(128),
+                           options: Options(vec![]),
                        },
                        &b""[..])));
 
-        assert_eq!(parser(DiffxParser::section_header).parse(&b"#.section:     \n"[..]),
+        assert_eq!(DiffxParser::section_header(&b"#.section:     \n"[..]),
                    Ok((SectionHeader {
                            depth: 1,
                            title: "section",
-                           options: hashmap!{},
+                           version: None,
+                           encoding: None,
+                           content_length: None,
+                           options: Options(vec![]),
                        },
                        &b""[..])));
 
-        assert_eq!(parser(DiffxParser::section_header)
-                       .parse(&b"#.section:   encoding=utf-8   \n"[..]),
+        assert_eq!(DiffxParser::section_header(&b"#.section:   encoding=utf-8   \n"[..]),
                    Ok((SectionHeader {
                            depth: 1,
                            title: "section",
-                           options: hashmap!{ "encoding" => "utf-8" },
+                           version: None,
+                           encoding: Some("utf-8"),
+                           content_length: None,
+                           options: Options(vec![]),
                        },
                        &b""[..])));
     }
 
     #[test]
     fn test_section() {
-        assert_eq!(DiffxParser::section(0, Encoding::Binary)
-                       .parse(&b"#diffx: version=1.0,encoding=utf-8,content-length=0\n\n"[..]),
+        assert_eq!(DiffxParser::section(&b"#diffx: version=1.0,encoding=utf-8,content-length=0\n\n"[..],
+                                         0,
+                                         Encoding::Binary),
                    Ok((("diffx",
                         Section {
                             encoding: Encoding::Utf8,
-                            options: hashmap!{
-                                "version" => "1.0",
-                                "encoding" => "utf-8",
-                                "content-length" => "0",
-                            },
+                            version: Some("1.0"),
+                            content_length: Some(0),
+                            options: Options(vec![]),
+                            content_encoding: ContentEncoding::Identity,
+                            decoded_length: 0,
                             content: EncodedData(""),
                         }),
                        &b""[..])));
 
-        assert_eq!(DiffxParser::section(0, Encoding::Binary).parse(&b"\
+        assert_eq!(DiffxParser::section(&b"\
 #diffx: version=1.0,encoding=utf-8
 #.foo: content-length=14
 Hello, \xE4\xB8\x96\xE7\x95\x8C
@@ -332,33 +825,41 @@ Hello, \xE4\xB8\x96\xE7\x95\x8C
 #.bar: content-length=16,encoding=binary
 Goodbye, world!
 
-"[..]),
+"[..],
+                                        0,
+                                        Encoding::Binary),
                    Ok((("diffx",
                         Section {
                             encoding: Encoding::Utf8,
-                            options: hashmap!{
-                                "version" => "1.0",
-                                "encoding" => "utf-8",
-                            },
+                            version: Some("1.0"),
+                            content_length: None,
+                            options: Options(vec![]),
+                            content_encoding: ContentEncoding::Identity,
+                            decoded_length: 0,
                             content: ChildSections(hashmap!{
                                 "foo" => Section {
                                     encoding: Encoding::Utf8,
-                                    options: hashmap!{ "content-length" => "14" },
+                                    version: None,
+                                    content_length: Some(14),
+                                    options: Options(vec![]),
+                                    content_encoding: ContentEncoding::Identity,
+                                    decoded_length: 14,
                                     content: EncodedData("Hello, 世界\n")
                                 },
                                 "bar" => Section {
                                     encoding: Encoding::Binary,
-                                    options: hashmap!{
-                                        "content-length" => "16",
-                                        "encoding" => "binary",
-                                    },
+                                    version: None,
+                                    content_length: Some(16),
+                                    options: Options(vec![]),
+                                    content_encoding: ContentEncoding::Identity,
+                                    decoded_length: 16,
                                     content: RawData(&b"Goodbye, world!\n"[..])
                                 },
                             }),
                         }),
                        &b""[..])));
 
-        assert_eq!(DiffxParser::section(0, Encoding::Binary).parse(&b"\
+        assert_eq!(DiffxParser::section(&b"\
 #diffx: version=1.0,encoding=utf-8
 #.foo:
 #..bar: content-length=14
@@ -369,28 +870,43 @@ Goodbye, world!
 
 #.qux: content-length=0
 
-"[..]),
+"[..],
+                                        0,
+                                        Encoding::Binary),
                    Ok((("diffx",
                         Section {
                             encoding: Encoding::Utf8,
-                            options: hashmap!{
-                                "version" => "1.0",
-                                "encoding" => "utf-8",
-                            },
+                            version: Some("1.0"),
+                            content_length: None,
+                            options: Options(vec![]),
+                            content_encoding: ContentEncoding::Identity,
+                            decoded_length: 0,
                             content: ChildSections(hashmap!{
                                 "foo" => Section {
                                     encoding: Encoding::Utf8,
-                                    options: hashmap!{},
+                                    version: None,
+                                    content_length: None,
+                                    options: Options(vec![]),
+                                    content_encoding: ContentEncoding::Identity,
+                                    decoded_length: 0,
                                     content: ChildSections(hashmap!{
                                         "bar" => Section {
                                             encoding: Encoding::Utf8,
-                                            options: hashmap!{ "content-length" => "14" },
+                                            version: None,
+                                            content_length: Some(14),
+                                            options: Options(vec![]),
+                                            content_encoding: ContentEncoding::Identity,
+                                            decoded_length: 14,
                                             content: EncodedData("Hello, world!\n"),
 
                                         },
                                         "baz" => Section {
                                             encoding: Encoding::Utf8,
-                                            options: hashmap!{ "content-length" => "16" },
+                                            version: None,
+                                            content_length: Some(16),
+                                            options: Options(vec![]),
+                                            content_encoding: ContentEncoding::Identity,
+                                            decoded_length: 16,
                                             content: EncodedData("Goodbye, world!\n"),
 
                                         },
@@ -398,15 +914,213 @@ Goodbye, world!
                                 },
                                 "qux" => Section{
                                     encoding: Encoding::Utf8,
-                                    options: hashmap!{ "content-length" => "0" },
+                                    version: None,
+                                    content_length: Some(0),
+                                    options: Options(vec![]),
+                                    content_encoding: ContentEncoding::Identity,
+                                    decoded_length: 0,
                                     content: EncodedData(""),
                                 },
                             }),
                         }),
                        &b""[..])));
 
-        assert!(DiffxParser::section(0, Encoding::Binary)
-            .parse(&b"#diffx: version=1.0,encoding=utf-8\n\n"[..])
+        assert!(DiffxParser::section(&b"#diffx: version=1.0,encoding=utf-8\n\n"[..], 0, Encoding::Binary).is_err());
+    }
+
+    #[test]
+    fn test_legacy_entry_points_still_accept_duplicate_option_keys() {
+        // The legacy, pre-`ParseOptions` entry points must keep accepting
+        // duplicate option keys (last-wins) just as they did before
+        // `ParseOptions`/`strict` existed, even though they still hard-error
+        // on an unrecognized `encoding`.
+        let ((_, section), _) =
+            DiffxParser::section(&b"#diffx: version=1.0,version=2.0,content-length=0\n\n"[..],
+                                  0,
+                                  Encoding::Binary)
+                .expect("duplicate option keys should be accepted by legacy entry points");
+        assert_eq!(section.version, Some("2.0"));
+    }
+
+    #[test]
+    fn test_parse_with_options_lenient_encoding_fallback() {
+        let options = ParseOptions::default();
+        assert_eq!(DiffxParser::parse_with_options(&b"#diffx: encoding=made-up,content-length=5\nhello\n"[..],
+                                                    options)
+                       .map(|((_, section), rest)| (section.encoding, rest)),
+                   Ok((Encoding::Binary, &b""[..])));
+    }
+
+    #[test]
+    fn test_parse_with_options_strict_encoding_is_an_error() {
+        let options = ParseOptions { strict: true, ..ParseOptions::default() };
+        assert!(DiffxParser::parse_with_options(&b"#diffx: encoding=made-up,content-length=5\nhello\n"[..],
+                                                 options)
+            .is_err());
+    }
+
+    #[test]
+    fn test_parse_with_options_strict_rejects_duplicate_keys() {
+        let options = ParseOptions { strict: true, ..ParseOptions::default() };
+        assert!(DiffxParser::parse_with_options(&b"#diffx: version=1.0,version=2.0,content-length=0\n\n"[..],
+                                                 options)
+            .is_err());
+
+        let lenient = ParseOptions::default();
+        assert!(DiffxParser::parse_with_options(&b"#diffx: version=1.0,version=2.0,content-length=0\n\n"[..],
+                                                 lenient)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_lenient_duplicate_untyped_option_last_wins() {
+        // `Options::get` should resolve duplicates the same way the eagerly
+        // parsed `version`/`encoding`/`content-length` fields do under
+        // lenient (non-strict) `ParseOptions`: last declaration wins.
+        let ((_, section), _) =
+            DiffxParser::parse_with_options(&b"#diffx: foo=first,foo=second,content-length=0\n\n"[..],
+                                             ParseOptions::default())
+                .unwrap();
+        assert_eq!(section.options.get("foo"), Some("second"));
+    }
+
+    #[test]
+    fn test_parse_with_options_max_options() {
+        let options = ParseOptions { max_options: Some(1), ..ParseOptions::default() };
+        assert!(DiffxParser::parse_with_options(&b"#diffx: version=1.0,content-length=0\n\n"[..], options)
+            .is_err());
+    }
+
+    #[test]
+    fn test_parse_with_options_max_depth() {
+        let options = ParseOptions { max_depth: Some(0), ..ParseOptions::default() };
+        assert!(DiffxParser::parse_with_options(&b"\
+#diffx: encoding=utf-8
+#.foo: content-length=5
+hello
+
+"[..],
+                                                 options)
             .is_err());
     }
+
+    #[test]
+    fn test_parse_with_options_max_sections() {
+        // The root `diffx` section itself already counts as one section, so
+        // a limit of 1 rejects the document before `foo` can be parsed.
+        let options = ParseOptions { max_sections: Some(1), ..ParseOptions::default() };
+        assert!(DiffxParser::parse_with_options(&b"\
+#diffx: encoding=utf-8
+#.foo: content-length=5
+hello
+
+"[..],
+                                                 options)
+            .is_err());
+    }
+
+    #[test]
+    fn test_parse_with_options_max_sections_exceeded_by_a_child_is_an_error() {
+        // A resource-limit error raised while collecting a later child
+        // section must propagate, not be swallowed as "no more children"
+        // and returned as a truncated but otherwise successful parse.
+        let options = ParseOptions { max_sections: Some(2), ..ParseOptions::default() };
+        assert!(DiffxParser::parse_with_options(&b"\
+#diffx: encoding=utf-8
+#.foo: content-length=5
+hello
+#.bar: content-length=5
+world
+
+"[..],
+                                                 options)
+            .is_err());
+    }
+
+    #[test]
+    fn test_content_encoding_unknown_is_an_error() {
+        assert!(DiffxParser::section(&b"#diffx: content-encoding=made-up,content-length=5\nhello\n"[..],
+                                      0,
+                                      Encoding::Binary)
+            .is_err());
+    }
+
+    #[cfg(not(feature = "flate2"))]
+    #[test]
+    fn test_content_encoding_gzip_without_flate2_feature_is_an_error() {
+        assert!(DiffxParser::section(&b"#diffx: content-encoding=gzip,content-length=5\nhello\n"[..],
+                                      0,
+                                      Encoding::Binary)
+            .is_err());
+    }
+
+    #[cfg(feature = "flate2")]
+    #[test]
+    fn test_content_encoding_gzip_round_trip() {
+        use std::io::Write;
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all("Hello, 世界\n".as_bytes()).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let document = format!("#diffx: content-encoding=gzip,content-length={},encoding=utf-8\n",
+                                compressed.len())
+            .into_bytes()
+            .into_iter()
+            .chain(compressed)
+            .chain(b"\n".iter().cloned())
+            .collect::<Vec<u8>>();
+
+        let ((_, section), rest) = DiffxParser::section(&document[..], 0, Encoding::Binary)
+            .expect("gzip-compressed section should parse");
+
+        assert_eq!(rest, &b""[..]);
+        assert_eq!(section.content_encoding, ContentEncoding::Gzip);
+        assert_eq!(section.decoded_length, "Hello, 世界\n".len());
+        assert_eq!(section.content, DecodedEncodedData("Hello, 世界\n".to_string()));
+    }
+
+    #[cfg(feature = "flate2")]
+    #[test]
+    fn test_parse_with_options_max_decoded_length_exceeded_is_an_error() {
+        use std::io::Write;
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all("Hello, 世界\n".as_bytes()).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let document = format!("#diffx: content-encoding=gzip,content-length={},encoding=utf-8\n",
+                                compressed.len())
+            .into_bytes()
+            .into_iter()
+            .chain(compressed)
+            .chain(b"\n".iter().cloned())
+            .collect::<Vec<u8>>();
+
+        let options = ParseOptions { max_decoded_length: Some(4), ..ParseOptions::default() };
+        assert!(DiffxParser::parse_with_options(&document[..], options).is_err());
+    }
+
+    #[cfg(feature = "flate2")]
+    #[test]
+    fn test_parse_with_options_max_decoded_length_not_exceeded_still_parses() {
+        use std::io::Write;
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(b"hello\n").unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let document = format!("#diffx: content-encoding=gzip,content-length={},encoding=utf-8\n",
+                                compressed.len())
+            .into_bytes()
+            .into_iter()
+            .chain(compressed)
+            .chain(b"\n".iter().cloned())
+            .collect::<Vec<u8>>();
+
+        let options = ParseOptions { max_decoded_length: Some(6), ..ParseOptions::default() };
+        let ((_, section), _) = DiffxParser::parse_with_options(&document[..], options)
+            .expect("decoded length exactly at the limit should still parse");
+        assert_eq!(section.decoded_length, 6);
+    }
 }
@@ -0,0 +1,361 @@
+//! Serialization of DiffX documents.
+//!
+//! The parser in [`lib.rs`][super] produces a borrowed [`Section`][super::Section]
+//! tree from an existing document. This module provides the inverse: an owned
+//! tree that can be built up programmatically and serialized back into the
+//! DiffX text format.
+
+use std::io;
+use std::io::Write;
+
+use Encoding;
+use grammar::{is_option_char, is_section_header_char};
+
+/// The content of a section being built.
+///
+/// Mirrors [`SectionContent`][super::SectionContent], but owns its data so it
+/// can be constructed without a backing buffer to borrow from.
+#[derive(Debug, PartialEq, Eq)]
+enum SectionBuilderContent {
+    /// One or more child sections, in the order they were added.
+    ChildSections(Vec<SectionBuilder>),
+
+    /// Encoded data.
+    EncodedData(String),
+
+    /// Raw binary data.
+    RawData(Vec<u8>),
+}
+
+/// A builder for a single section of a DiffX document.
+///
+/// A `SectionBuilder` is constructed with [`SectionBuilder::new`] and then
+/// given content with one of [`with_encoded_data`][SectionBuilder::with_encoded_data],
+/// [`with_raw_data`][SectionBuilder::with_raw_data], or
+/// [`with_child`][SectionBuilder::with_child]. Options other than `encoding`
+/// and `content-length` (which are managed automatically) can be attached
+/// with [`option`][SectionBuilder::option].
+#[derive(Debug, PartialEq, Eq)]
+pub struct SectionBuilder {
+    title: String,
+    encoding: Option<Encoding>,
+    options: Vec<(String, String)>,
+    content: SectionBuilderContent,
+}
+
+impl SectionBuilder {
+    /// Create a new, empty section builder with the given title.
+    ///
+    /// The section has no content until one of `with_encoded_data`,
+    /// `with_raw_data`, or `with_child` is called.
+    ///
+    /// Panics if `title` contains a byte the DiffX grammar does not allow in
+    /// a section title; otherwise the title would be written byte-for-byte
+    /// into the header line and could inject its own `:`, `,`, `=`, or even
+    /// a fake extra header via an embedded `\n`.
+    pub fn new<S: Into<String>>(title: S) -> SectionBuilder {
+        let title = title.into();
+        assert_valid_section_header_str("section title", &title);
+        SectionBuilder {
+            title: title,
+            encoding: None,
+            options: Vec::new(),
+            content: SectionBuilderContent::ChildSections(Vec::new()),
+        }
+    }
+
+    /// Set an additional option on this section's header.
+    ///
+    /// `encoding` and `content-length` are managed automatically and should
+    /// not be set through this method.
+    ///
+    /// Panics if `key` or `value` contains a byte the DiffX grammar does not
+    /// allow in an option key or value, for the same reason `new` validates
+    /// the title.
+    pub fn option<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> SectionBuilder {
+        let key = key.into();
+        let value = value.into();
+        assert_valid_option_str("option key", &key);
+        assert_valid_option_str("option value", &value);
+        self.options.push((key, value));
+        self
+    }
+
+    /// Explicitly set this section's encoding.
+    ///
+    /// If unset, the section inherits the encoding of its parent when
+    /// serialized, just as the parser does when reading a document that
+    /// omits the `encoding` option.
+    pub fn encoding(mut self, encoding: Encoding) -> SectionBuilder {
+        self.encoding = Some(encoding);
+        self
+    }
+
+    /// Give this section UTF-8 encoded data content.
+    ///
+    /// This also sets the section's encoding to [`Encoding::Utf8`], since
+    /// the written `encoding` option must match the content actually
+    /// serialized rather than whatever was inherited or set earlier.
+    ///
+    /// Panics if this section already has child sections; a section may
+    /// have child sections or data, but not both.
+    pub fn with_encoded_data<S: Into<String>>(mut self, data: S) -> SectionBuilder {
+        assert_no_children(&self.content);
+        self.content = SectionBuilderContent::EncodedData(data.into());
+        self.encoding = Some(Encoding::Utf8);
+        self
+    }
+
+    /// Give this section raw binary data content.
+    ///
+    /// This also sets the section's encoding to [`Encoding::Binary`], since
+    /// the written `encoding` option must match the content actually
+    /// serialized rather than whatever was inherited or set earlier.
+    ///
+    /// Panics if this section already has child sections; a section may
+    /// have child sections or data, but not both.
+    pub fn with_raw_data<D: Into<Vec<u8>>>(mut self, data: D) -> SectionBuilder {
+        assert_no_children(&self.content);
+        self.content = SectionBuilderContent::RawData(data.into());
+        self.encoding = Some(Encoding::Binary);
+        self
+    }
+
+    /// Add a child section.
+    ///
+    /// Panics if this section already has data content; a section may have
+    /// child sections or data, but not both.
+    pub fn with_child(mut self, child: SectionBuilder) -> SectionBuilder {
+        match self.content {
+            SectionBuilderContent::ChildSections(ref mut children) => {
+                children.push(child);
+                return self;
+            }
+            _ => panic!("a section cannot have both data and child sections"),
+        }
+    }
+
+    fn write_to<W: Write>(&self, w: &mut W, depth: usize, parent_encoding: Encoding) -> io::Result<()> {
+        let encoding = self.encoding.unwrap_or(parent_encoding);
+
+        write!(w, "#")?;
+        for _ in 0..depth {
+            write!(w, ".")?;
+        }
+        write!(w, "{}:", self.title)?;
+
+        let mut options = self.options.clone();
+        if self.encoding.is_some() {
+            options.push(("encoding".into(), encoding_str(encoding).into()));
+        }
+
+        let content_length = match self.content {
+            SectionBuilderContent::EncodedData(ref data) => Some(data.len()),
+            SectionBuilderContent::RawData(ref data) => Some(data.len()),
+            SectionBuilderContent::ChildSections(_) => None,
+        };
+        if let Some(content_length) = content_length {
+            options.push(("content-length".into(), content_length.to_string()));
+        }
+
+        if !options.is_empty() {
+            write!(w, " ")?;
+            for (i, &(ref key, ref value)) in options.iter().enumerate() {
+                if i > 0 {
+                    write!(w, ",")?;
+                }
+                write!(w, "{}={}", key, value)?;
+            }
+        }
+        write!(w, "\n")?;
+
+        match self.content {
+            SectionBuilderContent::EncodedData(ref data) => {
+                w.write_all(data.as_bytes())?;
+                write!(w, "\n")?;
+            }
+            SectionBuilderContent::RawData(ref data) => {
+                w.write_all(data)?;
+                write!(w, "\n")?;
+            }
+            SectionBuilderContent::ChildSections(ref children) => {
+                for child in children {
+                    child.write_to(w, depth + 1, encoding)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Mirrors the panic `with_child` raises in the opposite direction: a
+// section cannot have both data and child sections. An untouched, still
+// empty `ChildSections` (the state every `SectionBuilder` starts in) is not
+// considered a conflict.
+fn assert_no_children(content: &SectionBuilderContent) {
+    if let SectionBuilderContent::ChildSections(ref children) = *content {
+        if !children.is_empty() {
+            panic!("a section cannot have both data and child sections");
+        }
+    }
+}
+
+fn encoding_str(encoding: Encoding) -> &'static str {
+    match encoding {
+        Encoding::Utf8 => "utf-8",
+        Encoding::Binary => "binary",
+    }
+}
+
+fn assert_valid_section_header_str(kind: &str, s: &str) {
+    if !s.bytes().all(is_section_header_char) {
+        panic!("{} {:?} contains a character not allowed in a DiffX section title", kind, s);
+    }
+}
+
+fn assert_valid_option_str(kind: &str, s: &str) {
+    if s.is_empty() || !s.bytes().all(is_option_char) {
+        panic!("{} {:?} must be non-empty and contain only characters allowed in a DiffX option", kind, s);
+    }
+}
+
+/// A builder for an entire DiffX document.
+///
+/// A document is simply its root `diffx` section, which defaults to
+/// [`Encoding::Binary`][super::Encoding::Binary] if no encoding is given, as
+/// the parser does for top-level documents.
+#[derive(Debug, PartialEq, Eq)]
+pub struct DocumentBuilder(SectionBuilder);
+
+impl DocumentBuilder {
+    /// Create a new, empty document builder.
+    pub fn new() -> DocumentBuilder {
+        DocumentBuilder(SectionBuilder::new("diffx"))
+    }
+
+    /// Set an additional option on the root `diffx` section's header.
+    pub fn option<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> DocumentBuilder {
+        self.0 = self.0.option(key, value);
+        self
+    }
+
+    /// Explicitly set the root section's encoding.
+    pub fn encoding(mut self, encoding: Encoding) -> DocumentBuilder {
+        self.0 = self.0.encoding(encoding);
+        self
+    }
+
+    /// Add a top-level child section.
+    pub fn with_child(mut self, child: SectionBuilder) -> DocumentBuilder {
+        self.0 = self.0.with_child(child);
+        self
+    }
+
+    /// Serialize this document, writing it to `w`.
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.0.write_to(w, 0, Encoding::Binary)
+    }
+
+    /// Serialize this document into a newly allocated byte vector.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        // Writing to a `Vec<u8>` cannot fail.
+        self.write_to(&mut bytes).unwrap();
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {DiffxParser, Encoding, Section, SectionContent};
+
+    #[test]
+    fn test_write_leaf() {
+        let doc = DocumentBuilder::new()
+            .option("version", "1.0")
+            .encoding(Encoding::Utf8)
+            .with_child(SectionBuilder::new("foo").with_encoded_data("Hello, world!\n"));
+
+        assert_eq!(doc.to_bytes(),
+                   &b"#diffx: version=1.0,encoding=utf-8\n\
+                      #.foo: encoding=utf-8,content-length=14\n\
+                      Hello, world!\n\
+                      \n"[..]);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let doc = DocumentBuilder::new()
+            .option("version", "1.0")
+            .encoding(Encoding::Utf8)
+            .with_child(SectionBuilder::new("foo").with_encoded_data("Hello, 世界\n"))
+            .with_child(SectionBuilder::new("bar")
+                            .encoding(Encoding::Binary)
+                            .with_raw_data(&b"Goodbye, world!\n"[..]));
+
+        let bytes = doc.to_bytes();
+
+        let ((_, parsed), _) = DiffxParser::section(&bytes[..], 0, Encoding::Binary)
+            .expect("round-tripped document should parse");
+
+        match parsed.content {
+            SectionContent::ChildSections(ref children) => {
+                assert_eq!(children.get("foo").map(|s: &Section| &s.content),
+                           Some(&SectionContent::EncodedData("Hello, 世界\n")));
+                assert_eq!(children.get("bar").map(|s: &Section| &s.content),
+                           Some(&SectionContent::RawData(&b"Goodbye, world!\n"[..])));
+            }
+            ref other => panic!("expected child sections, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_with_raw_data_overrides_inherited_encoding() {
+        let doc = DocumentBuilder::new()
+            .encoding(Encoding::Utf8)
+            .with_child(SectionBuilder::new("foo").with_raw_data(vec![0xFF, 0xFE, 0]));
+
+        let bytes = doc.to_bytes();
+
+        let ((_, parsed), _) = DiffxParser::section(&bytes[..], 0, Encoding::Binary)
+            .expect("document with raw data nested under a UTF-8 parent should still parse");
+
+        match parsed.content {
+            SectionContent::ChildSections(ref children) => {
+                assert_eq!(children.get("foo").map(|s: &Section| &s.content),
+                           Some(&SectionContent::RawData(&[0xFF, 0xFE, 0][..])));
+            }
+            ref other => panic!("expected child sections, got {:?}", other),
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_new_rejects_title_with_newline() {
+        SectionBuilder::new("foo\n#.evil: content-length=0");
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_option_rejects_value_with_comma_and_equals() {
+        SectionBuilder::new("foo").option("k", "v=1,injected=2");
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_with_encoded_data_rejects_existing_children() {
+        SectionBuilder::new("foo")
+            .with_child(SectionBuilder::new("bar").with_encoded_data("baz"))
+            .with_encoded_data("overwrite me");
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_with_raw_data_rejects_existing_children() {
+        SectionBuilder::new("foo")
+            .with_child(SectionBuilder::new("bar").with_encoded_data("baz"))
+            .with_raw_data(vec![0]);
+    }
+}